@@ -0,0 +1,93 @@
+use std::cell::Cell;
+use std::hint;
+use std::thread;
+
+// Spinning with a fixed number of spin_loop hints between the checks
+// either checks too often (and hammers the cache line other cores need)
+// or sleeps through the moment the value changes. Backoff starts with
+// a single hint and doubles the number every round, up to a cap.
+//
+// The thread that can't make progress goes like this:
+// - spin, when the other thread is expected to finish very soon;
+// - snooze, same as spin at first, then yields the CPU to the OS;
+// - once is_completed, stops and does a proper (syscall) wait.
+//
+// It's a Cell inside, a Backoff is meant to be a local of a single
+// spin loop and not to be shared between threads.
+pub struct Backoff {
+    step: Cell<u32>,
+}
+
+// 1 << 6 = 64 spin_loop hints in the longest round.
+const SPIN_LIMIT: u32 = 6;
+// A few rounds of yield_now after the spinning is maxed out.
+const YIELD_LIMIT: u32 = 10;
+
+impl Backoff {
+    pub const fn new() -> Self {
+        Self { step: Cell::new(0) }
+    }
+
+    // Back to the shortest round, e.g. after some progress was made.
+    pub fn reset(&self) {
+        self.step.set(0);
+    }
+
+    // One round of busy waiting. Returns the number of spin_loop hints
+    // it did, for the callers that have their own limit on those.
+    pub fn spin(&self) -> u32 {
+        let spins = 1 << self.step.get().min(SPIN_LIMIT);
+        for _ in 0..spins {
+            hint::spin_loop();
+        }
+        if self.step.get() <= SPIN_LIMIT {
+            self.step.set(self.step.get() + 1);
+        }
+        spins
+    }
+
+    // Same as spin, until the rounds get as long as they go. After that
+    // we are better off letting another thread (maybe the one we are
+    // waiting for) run on this core.
+    pub fn snooze(&self) {
+        if self.step.get() <= SPIN_LIMIT {
+            for _ in 0..1 << self.step.get() {
+                hint::spin_loop();
+            }
+        } else {
+            thread::yield_now();
+        }
+        if self.step.get() <= YIELD_LIMIT {
+            self.step.set(self.step.get() + 1);
+        }
+    }
+
+    // True when snoozing didn't help and it's time to block instead.
+    pub fn is_completed(&self) -> bool {
+        self.step.get() > YIELD_LIMIT
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn completes_after_snoozing() {
+    let backoff = Backoff::new();
+    let mut snoozes = 0;
+    while !backoff.is_completed() {
+        backoff.snooze();
+        snoozes += 1;
+        assert!(snoozes <= YIELD_LIMIT + 1);
+    }
+    assert_eq!(snoozes, YIELD_LIMIT + 1);
+
+    // Spinning alone never gets there, the rounds just stop growing.
+    backoff.reset();
+    let rounds: Vec<u32> = (0..10).map(|_| backoff.spin()).collect();
+    assert_eq!(rounds, [1, 2, 4, 8, 16, 32, 64, 64, 64, 64]);
+    assert!(!backoff.is_completed());
+}
@@ -0,0 +1,54 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The same workload for any lock, so the times it returns can be
+// compared: every thread locks `iters` times and increments the counter
+// that is behind the lock.
+//
+// lock_fn does one lock-increment-unlock. It's a closure so that locks
+// with different APIs fit in: our Mutex returns a LockResult, the
+// SpinLock a plain guard, and so on.
+//
+// The times are only meaningful with --release. On a machine with fewer cores than threads the spin lock looks much
+// worse than it would otherwise, the holder can get preempted while
+// the others spin through their time slices.
+pub fn bench_mutex<M, F>(mutex: &M, threads: usize, iters: usize, lock_fn: F) -> Duration
+where
+    M: Sync,
+    F: Fn(&M) + Sync,
+{
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                for _ in 0..iters {
+                    lock_fn(mutex);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+// Checks that every lock gets the same work done. The times themselves
+// are not compared, that depends too much on the machine.
+#[test]
+fn same_workload() {
+    use crate::ch4_spin_lock::s4_load_first::SpinLock;
+    use crate::ch9_locks::mutex_3::Mutex;
+
+    const THREADS: usize = 4;
+    const ITERS: usize = 1000;
+
+    let mutex = Mutex::new(0);
+    bench_mutex(&mutex, THREADS, ITERS, |m| *m.lock().unwrap() += 1);
+    assert_eq!(mutex.into_inner(), THREADS * ITERS);
+
+    let spin_lock = SpinLock::new(0);
+    bench_mutex(&spin_lock, THREADS, ITERS, |m| *m.lock() += 1);
+    assert_eq!(*spin_lock.lock(), THREADS * ITERS);
+
+    let std_mutex = std::sync::Mutex::new(0);
+    bench_mutex(&std_mutex, THREADS, ITERS, |m| *m.lock().unwrap() += 1);
+    assert_eq!(std_mutex.into_inner().unwrap(), THREADS * ITERS);
+}
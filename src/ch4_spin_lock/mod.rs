@@ -1,3 +1,4 @@
 pub mod s1_minimal;
 pub mod s2_unsafe;
 pub mod s3_guard;
+pub mod s4_load_first;
@@ -0,0 +1,108 @@
+use std::ops::{Deref, DerefMut};
+use std::cell::UnsafeCell;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// Same lock as in s3_guard, but meant to be reused by the rest of the crate.
+//
+// The difference is in how it spins. The swap in s3_guard is a store even
+// when it fails, so every spinning thread keeps taking the cache line in
+// the exclusive state and invalidating it for everyone else, including the
+// thread that holds the lock. See ch7-02-caching.rs for the numbers.
+//
+// Here a waiting thread spins on a plain load. All the waiters can share
+// the cache line while the lock is taken, and only when it looks free do
+// they try the compare_exchange.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T> Sync for SpinLock<T> where T: Send {}
+
+pub struct SpinGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+unsafe impl<T> Sync for SpinGuard<'_, T> where T: Sync {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinGuard<'_, T> {
+        // The first attempt goes straight to compare_exchange,
+        // an uncontended lock is the common case.
+        while self.locked.compare_exchange_weak(false, true, Acquire, Relaxed).is_err() {
+            // Relaxed is enough here, the Acquire happens
+            // in the compare_exchange that actually takes the lock.
+            while self.locked.load(Relaxed) {
+                std::hint::spin_loop();
+            }
+        }
+        SpinGuard { lock: self }
+    }
+
+    // A single attempt, no spinning. Not weak, since a spurious
+    // failure would look like contention to the caller.
+    pub fn try_lock(&self) -> Option<SpinGuard<'_, T>> {
+        if self.locked.compare_exchange(false, true, Acquire, Relaxed).is_ok() {
+            Some(SpinGuard { lock: self })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Deref for SpinGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // Safety: The very existence of this Guard
+        // guarantees we've exclusively locked the lock.
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Release);
+    }
+}
+
+#[test]
+fn main() {
+    use std::thread;
+    let counter = SpinLock::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1_000_000 {
+                    *counter.lock() += 1;
+                }
+            });
+        }
+    });
+    assert_eq!(*counter.lock(), 4_000_000);
+}
+
+#[test]
+fn try_lock() {
+    let lock = SpinLock::new(1);
+    let guard = lock.try_lock().unwrap();
+    assert!(lock.try_lock().is_none());
+    drop(guard);
+    *lock.try_lock().unwrap() += 1;
+    assert_eq!(*lock.lock(), 2);
+}
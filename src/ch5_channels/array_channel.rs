@@ -0,0 +1,368 @@
+use std::cell::{Cell, UnsafeCell};
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
+use std::sync::atomic::{fence, AtomicPtr, AtomicUsize};
+use std::thread::{self, Thread};
+
+// A bounded channel that doesn't allocate. The buffer is a part of the
+// struct itself, so it can live on the stack or in a static.
+//
+// It is a ring buffer for a single producer and a single consumer.
+// `head` and `tail` only ever grow (wrapping around usize), the slot
+// is the index modulo N. The producer is the only one who writes
+// `tail` and the consumer is the only one who writes `head`.
+pub struct ArrayChannel<T, const N: usize> {
+    buffer: [UnsafeCell<MaybeUninit<T>>; N],
+    /// Index of the next message to pop.
+    head: AtomicUsize,
+    /// Index of the next slot to push to.
+    tail: AtomicUsize,
+    /// The producer's thread while it's blocked in send, null otherwise.
+    producer_thread: AtomicPtr<Thread>,
+    /// The consumer's thread while it's blocked in receive, null otherwise.
+    consumer_thread: AtomicPtr<Thread>,
+}
+
+unsafe impl<T, const N: usize> Sync for ArrayChannel<T, N> where T: Send {}
+
+// Same idea as in s5_borrowing - the borrow checker makes sure there is
+// only one producer and one consumer. That's what makes it sound to touch
+// the slots without any locks.
+pub struct Producer<'a, T, const N: usize> {
+    channel: &'a ArrayChannel<T, N>,
+    /// Free slots promised to permits that are not used or dropped yet.
+    reserved: Cell<usize>,
+}
+
+pub struct Consumer<'a, T, const N: usize> {
+    channel: &'a ArrayChannel<T, N>,
+}
+
+impl<T, const N: usize> ArrayChannel<T, N> {
+    pub const fn new() -> Self {
+        assert!(N > 0, "capacity must be at least 1");
+        Self {
+            buffer: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            producer_thread: AtomicPtr::new(ptr::null_mut()),
+            consumer_thread: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        let producer = Producer { channel: self, reserved: Cell::new(0) };
+        (producer, Consumer { channel: self })
+    }
+}
+
+impl<T, const N: usize> Default for ArrayChannel<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    // Gives the message back if the buffer is full.
+    pub fn push(&mut self, message: T) -> Result<(), T> {
+        if self.free_capacity() == 0 {
+            return Err(message);
+        }
+        self.write(message);
+        Ok(())
+    }
+
+    // How many more messages can be pushed right now, minus the slots
+    // held by permits. The consumer can only make more room, so for the
+    // producer this is a lower bound, never too much.
+    pub fn free_capacity(&self) -> usize {
+        let channel = self.channel;
+        // Nobody else writes the tail, so Relaxed is enough.
+        let tail = channel.tail.load(Relaxed);
+        // Acquire matches the Release in pop, the consumer is done
+        // reading the slot before we are going to overwrite it.
+        let head = channel.head.load(Acquire);
+        N - tail.wrapping_sub(head) - self.reserved.get()
+    }
+
+    // The caller has made sure there is room, with free_capacity
+    // or with a permit that holds a slot.
+    fn write(&self, message: T) {
+        let channel = self.channel;
+        let tail = channel.tail.load(Relaxed);
+        // Safety: The slot is outside of head..tail, so the consumer doesn't touch it.
+        unsafe { (*channel.buffer[tail % N].get()).write(message) };
+        // Release makes the message visible before the consumer sees the new tail.
+        channel.tail.store(tail.wrapping_add(1), Release);
+        unpark(&channel.consumer_thread);
+    }
+
+    // Same as push, but waits for room instead of giving the message back.
+    pub fn send(&mut self, mut message: T) {
+        loop {
+            match self.push(message) {
+                Ok(()) => return,
+                Err(m) => message = m,
+            }
+            let channel = self.channel;
+            park_while(&channel.producer_thread, || {
+                channel.tail.load(Relaxed).wrapping_sub(channel.head.load(Relaxed)) == N
+            });
+        }
+    }
+}
+
+// A free slot held for one message. Filling it can't fail and can't block.
+//
+// There is still one producer, the permits are only its promises to
+// itself. They borrow the producer, so there is no push or send while
+// any of them is around, and the producer counts them. That count is
+// subtracted from the free capacity, so there are never more permits
+// than free slots. Which slot a permit gets is decided when it's used:
+// messages go in the order they are sent, not the order of reserve.
+//
+// The count is a Cell, not an atomic. That makes the producer !Sync,
+// so the permits can't leave its thread and two of them are never
+// filled at the same time. A permit that is dropped unused gives its
+// slot back.
+pub struct Permit<'p, 'a, T, const N: usize> {
+    producer: &'p Producer<'a, T, N>,
+}
+
+impl<'a, T, const N: usize> Producer<'a, T, N> {
+    // Useful when making the message is expensive and there is no point
+    // in doing that if it can't be sent right away.
+    pub fn reserve(&self) -> Option<Permit<'_, 'a, T, N>> {
+        if self.free_capacity() == 0 {
+            return None;
+        }
+        self.reserved.set(self.reserved.get() + 1);
+        Some(Permit { producer: self })
+    }
+}
+
+impl<T, const N: usize> Permit<'_, '_, T, N> {
+    pub fn send(self, message: T) {
+        // The slot is taken by the message now, and drop gives
+        // back the reservation, so the free capacity stays the same.
+        self.producer.write(message);
+    }
+}
+
+impl<T, const N: usize> Drop for Permit<'_, '_, T, N> {
+    fn drop(&mut self) {
+        let reserved = &self.producer.reserved;
+        reserved.set(reserved.get() - 1);
+    }
+}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    pub fn pop(&mut self) -> Option<T> {
+        let channel = self.channel;
+        let head = channel.head.load(Relaxed);
+        // Acquire matches the Release in push, the message is written.
+        let tail = channel.tail.load(Acquire);
+        if head == tail {
+            return None;
+        }
+        // Safety: The slot is inside of head..tail, so it holds a message
+        // and the producer doesn't touch it until we move the head.
+        let message = unsafe { (*channel.buffer[head % N].get()).assume_init_read() };
+        channel.head.store(head.wrapping_add(1), Release);
+        unpark(&channel.producer_thread);
+        Some(message)
+    }
+
+    // Same as pop, but waits for a message instead of returning None.
+    pub fn receive(&mut self) -> T {
+        loop {
+            if let Some(message) = self.pop() {
+                return message;
+            }
+            let channel = self.channel;
+            park_while(&channel.consumer_thread, || {
+                channel.head.load(Relaxed) == channel.tail.load(Relaxed)
+            });
+        }
+    }
+}
+
+// The blocking side puts its Thread where the other side can find it and
+// parks, the other side takes it out after every push or pop and unparks.
+// Unlike s6 we don't know the threads at split time, the producer and the
+// consumer can be moved anywhere after that. And a Thread doesn't fit in an
+// atomic, so it's boxed and the atomic holds the pointer.
+//
+// Whoever swaps the pointer out owns the box and frees it. So the thread
+// is never unparked through a box that is already gone, and a box is freed
+// exactly once, by the other side or by the blocked thread itself.
+//
+// The check is racy on purpose, that's what the fences are for. It's the
+// classic "store mine, load theirs" on both sides: we store the pointer
+// and load the index, they store the index and load the pointer. Without
+// SeqCst both loads could see the old values, we'd park on a ring that
+// has changed, and they wouldn't see anyone to unpark. With the fences at
+// least one of us sees the other. If they see us before we park, the
+// unpark is remembered by the thread and the park returns right away.
+fn park_while(slot: &AtomicPtr<Thread>, blocked: impl Fn() -> bool) {
+    let thread = Box::into_raw(Box::new(thread::current()));
+    // Release so that the other side gets a complete Thread.
+    slot.store(thread, Release);
+    fence(SeqCst);
+    if blocked() {
+        thread::park();
+    }
+    // Not taken yet, so nobody unparked us. Take it back, we are done.
+    let thread = slot.swap(ptr::null_mut(), Acquire);
+    if !thread.is_null() {
+        drop(unsafe { Box::from_raw(thread) });
+    }
+}
+
+fn unpark(slot: &AtomicPtr<Thread>) {
+    fence(SeqCst);
+    // Cheap check first, most of the time nobody is blocked.
+    if slot.load(Relaxed).is_null() {
+        return;
+    }
+    let thread = slot.swap(ptr::null_mut(), Acquire);
+    if !thread.is_null() {
+        unsafe { Box::from_raw(thread) }.unpark();
+    }
+}
+
+impl<T, const N: usize> Drop for ArrayChannel<T, N> {
+    fn drop(&mut self) {
+        // Drop whatever was pushed but never popped.
+        let tail = *self.tail.get_mut();
+        let mut head = *self.head.get_mut();
+        while head != tail {
+            unsafe { self.buffer[head % N].get_mut().assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+#[test]
+fn fifo_across_threads() {
+    let mut channel = ArrayChannel::<u32, 4>::new();
+    thread::scope(|s| {
+        let (mut producer, mut consumer) = channel.split();
+        s.spawn(move || {
+            for i in 0..1000 {
+                let mut message = i;
+                while let Err(m) = producer.push(message) {
+                    message = m;
+                    thread::yield_now();
+                }
+            }
+        });
+        for i in 0..1000 {
+            loop {
+                if let Some(message) = consumer.pop() {
+                    assert_eq!(message, i);
+                    break;
+                }
+                thread::yield_now();
+            }
+        }
+    });
+}
+
+#[test]
+fn blocking_send_receive() {
+    use std::time::Duration;
+
+    let mut channel = ArrayChannel::<u32, 2>::new();
+    thread::scope(|s| {
+        let (mut producer, mut consumer) = channel.split();
+        s.spawn(move || {
+            for i in 0..50 {
+                producer.send(i);
+            }
+        });
+        for i in 0..50 {
+            // A slow consumer, the producer fills the ring and has to wait.
+            if i % 10 == 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!(consumer.receive(), i);
+        }
+    });
+    assert!(channel.producer_thread.get_mut().is_null());
+    assert!(channel.consumer_thread.get_mut().is_null());
+}
+
+#[test]
+fn drops_leftovers() {
+    use std::sync::atomic::AtomicUsize;
+
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    // Capacity 1 is the edge case where head and tail always share a slot.
+    let mut one = ArrayChannel::<u32, 1>::new();
+    let (mut producer, mut consumer) = one.split();
+    assert!(producer.push(1).is_ok());
+    assert_eq!(producer.push(2), Err(2));
+    assert_eq!(consumer.pop(), Some(1));
+    assert_eq!(consumer.pop(), None);
+    assert!(producer.push(3).is_ok());
+
+    let mut channel = ArrayChannel::<DetectDrop, 4>::new();
+    let (mut producer, mut consumer) = channel.split();
+    for _ in 0..4 {
+        assert!(producer.push(DetectDrop).is_ok());
+    }
+    assert!(producer.push(DetectDrop).is_err());
+    // The rejected message is given back and dropped by us.
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    drop(consumer.pop());
+    assert_eq!(NUM_DROPS.load(Relaxed), 2);
+    // Three are left in the buffer.
+    drop(channel);
+    assert_eq!(NUM_DROPS.load(Relaxed), 5);
+}
+
+#[test]
+fn reserve_permit() {
+    let mut channel = ArrayChannel::<u32, 3>::new();
+    let (mut producer, mut consumer) = channel.split();
+
+    assert!(producer.push(1).is_ok());
+    assert_eq!(producer.free_capacity(), 2);
+
+    // Each permit holds a slot, even though nothing is written yet.
+    let first = producer.reserve().unwrap();
+    assert_eq!(producer.free_capacity(), 1);
+    let second = producer.reserve().unwrap();
+    assert_eq!(producer.free_capacity(), 0);
+    assert!(producer.reserve().is_none());
+
+    // The consumer makes room, that's not taken by the permits.
+    assert_eq!(consumer.pop(), Some(1));
+    assert_eq!(producer.free_capacity(), 1);
+
+    // Messages go in the order they are sent.
+    second.send(2);
+    assert_eq!(producer.free_capacity(), 1);
+    // An unused permit gives its slot back.
+    drop(first);
+    assert_eq!(producer.free_capacity(), 2);
+
+    assert!(producer.push(3).is_ok());
+    assert!(producer.push(4).is_ok());
+    assert_eq!(producer.push(5), Err(5));
+    assert_eq!(consumer.pop(), Some(2));
+    assert_eq!(consumer.pop(), Some(3));
+    assert_eq!(consumer.pop(), Some(4));
+    assert_eq!(producer.free_capacity(), 3);
+}
@@ -0,0 +1,201 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, PoisonError};
+use crate::ch9_locks::condvar_2::Condvar;
+use crate::ch9_locks::mutex_3::{Mutex, MutexGuard};
+
+// Every receiver gets every message, unlike the other channels where a
+// message goes to one receiver only. The messages are kept in one shared
+// log, and each receiver has its own cursor into it. A receive clones the
+// message out, the log keeps it for the others.
+//
+// Slow receivers: the log doesn't grow, it holds the last `capacity`
+// messages and a send to a full log drops the oldest one. Growing would
+// let a single stuck receiver eat all the memory, and the sender would
+// never know. So a receiver that fell behind more than that loses some
+// messages, and its next receive tells it how many instead of silently
+// skipping them. Same choice as the watch channel made, but with a
+// history of more than one value.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    /// The sequence number of the next message to receive.
+    next: u64,
+}
+
+struct Shared<T> {
+    log: Mutex<Log<T>>,
+    capacity: usize,
+    sent: Condvar,
+}
+
+struct Log<T> {
+    messages: VecDeque<T>,
+    /// The sequence number of messages[0]. Goes up when one is dropped.
+    first: u64,
+    /// Set when the sender is gone. What's in the log can still be received.
+    disconnected: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// The receiver fell behind and this many messages were dropped
+    /// before it got to them. The next receive goes on from the oldest
+    /// message that is still there.
+    Lagged(u64),
+    /// The sender is gone and everything was received.
+    Disconnected,
+}
+
+// How many messages broadcast keeps for the receivers that are behind.
+// Enough for a receiver that is a little slow now and then, small enough
+// that the clones don't add up to much.
+pub const DEFAULT_CAPACITY: usize = 16;
+
+pub fn broadcast<T: Clone>() -> (Sender<T>, Receiver<T>) {
+    broadcast_with_capacity(DEFAULT_CAPACITY)
+}
+
+// For when the receivers are expected to fall further behind than the
+// default allows, or a history that long is too much memory.
+pub fn broadcast_with_capacity<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "capacity must be at least 1");
+    let shared = Arc::new(Shared {
+        log: Mutex::new(Log {
+            messages: VecDeque::with_capacity(capacity),
+            first: 0,
+            disconnected: false,
+        }),
+        capacity,
+        sent: Condvar::new(),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared, next: 0 })
+}
+
+impl<T> Shared<T> {
+    // A clone that panics in a receive poisons the mutex, but the log
+    // itself is only read there and stays whole. So a panicking receiver
+    // doesn't take the sender and the other receivers down with it.
+    fn log(&self) -> MutexGuard<'_, Log<T>> {
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> Sender<T> {
+    pub fn send(&self, message: T) {
+        let mut log = self.shared.log();
+        if log.messages.len() == self.shared.capacity {
+            log.messages.pop_front();
+            log.first += 1;
+        }
+        log.messages.push_back(message);
+        drop(log);
+        self.shared.sent.notify_all();
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Under the mutex, so that a receiver that found nothing in the
+        // log and is about to wait can't miss it.
+        self.shared.log().disconnected = true;
+        self.shared.sent.notify_all();
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    // Blocks until there is a message this receiver hasn't seen yet.
+    pub fn receive(&mut self) -> Result<T, RecvError> {
+        let mut log = self.shared.log();
+        loop {
+            if self.next < log.first {
+                let missed = log.first - self.next;
+                self.next = log.first;
+                return Err(RecvError::Lagged(missed));
+            }
+            let index = (self.next - log.first) as usize;
+            if let Some(message) = log.messages.get(index) {
+                self.next += 1;
+                return Ok(message.clone());
+            }
+            if log.disconnected {
+                return Err(RecvError::Disconnected);
+            }
+            log = self.shared.sent.wait(log).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+// The clone starts where this receiver is now, and from there on they
+// each go at their own pace.
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Receiver { shared: self.shared.clone(), next: self.next }
+    }
+}
+
+#[test]
+fn every_receiver_gets_everything() {
+    use std::thread;
+
+    let (sender, receiver) = broadcast();
+    let receivers = [receiver.clone(), receiver];
+    thread::scope(|s| {
+        for mut receiver in receivers {
+            s.spawn(move || {
+                let mut seen = Vec::new();
+                while let Ok(message) = receiver.receive() {
+                    seen.push(message);
+                }
+                assert_eq!(seen, ["one", "two", "three"]);
+            });
+        }
+        for message in ["one", "two", "three"] {
+            sender.send(message);
+        }
+        drop(sender);
+    });
+
+    // A receiver that is too slow loses the oldest ones and is told so.
+    let (sender, mut receiver) = broadcast_with_capacity(2);
+    for i in 0..5 {
+        sender.send(i);
+    }
+    assert_eq!(receiver.receive(), Err(RecvError::Lagged(3)));
+    assert_eq!(receiver.receive(), Ok(3));
+    assert_eq!(receiver.receive(), Ok(4));
+    drop(sender);
+    assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
+}
+
+#[test]
+fn panicking_receiver_poisons_nobody() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    // The first clone panics, that happens under the lock in receive.
+    static PANICKED: AtomicBool = AtomicBool::new(false);
+    struct Fragile;
+    impl Clone for Fragile {
+        fn clone(&self) -> Self {
+            assert!(PANICKED.swap(true, Relaxed), "fragile message");
+            Fragile
+        }
+    }
+
+    let (sender, mut receiver) = broadcast();
+    let mut other = receiver.clone();
+    sender.send(Fragile);
+    assert!(catch_unwind(AssertUnwindSafe(|| receiver.receive())).is_err());
+    assert!(sender.shared.log.is_poisoned());
+
+    // The sender and the other receiver go on as if nothing happened.
+    sender.send(Fragile);
+    assert!(other.receive().is_ok());
+    assert!(other.receive().is_ok());
+    drop(sender);
+    assert_eq!(other.receive().err(), Some(RecvError::Disconnected));
+}
@@ -0,0 +1,131 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+const EMPTY: u8 = 0;
+const FULL: u8 = 1;
+const BUSY: u8 = 2;
+
+// A "latest value" slot. Unlike the channels in s3 and s5 it can be used
+// over and over again, and a set doesn't have to wait for the previous
+// message to be taken: the new one replaces it. A reader that comes late
+// gets only the freshest value, the ones in between are handed back to
+// whoever set over them.
+//
+// BUSY is a tiny spin lock around the slot. It's held only to move a
+// message in or out, so the other side spins for a copy at most. Any
+// number of threads can set and take.
+pub struct MailboxChannel<T> {
+    message: UnsafeCell<MaybeUninit<T>>,
+    state: AtomicU8,
+}
+
+unsafe impl<T: Send> Sync for MailboxChannel<T> {}
+
+impl<T> MailboxChannel<T> {
+    pub const fn new() -> Self {
+        Self {
+            message: UnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicU8::new(EMPTY),
+        }
+    }
+
+    // Returns the message that was there and was never taken, if any.
+    // It's the caller's now, so it's dropped exactly once, by them.
+    pub fn set(&self, message: T) -> Option<T> {
+        let was_full = self.lock() == FULL;
+        let slot = unsafe { &mut *self.message.get() };
+        let previous = if was_full {
+            Some(unsafe { slot.assume_init_read() })
+        } else {
+            None
+        };
+        slot.write(message);
+        self.state.store(FULL, Release);
+        previous
+    }
+
+    pub fn take(&self) -> Option<T> {
+        // Nothing to take, no need to lock.
+        if self.state.load(Relaxed) == EMPTY {
+            return None;
+        }
+        if self.lock() == EMPTY {
+            self.state.store(EMPTY, Release);
+            return None;
+        }
+        let message = unsafe { (*self.message.get()).assume_init_read() };
+        self.state.store(EMPTY, Release);
+        Some(message)
+    }
+
+    // Takes the slot and returns whether it was EMPTY or FULL.
+    // Acquire pairs with the Release stores above, the message that
+    // was written (or read out) is complete once we have the slot.
+    fn lock(&self) -> u8 {
+        let mut state = self.state.load(Relaxed);
+        loop {
+            if state == BUSY {
+                std::hint::spin_loop();
+                state = self.state.load(Relaxed);
+                continue;
+            }
+            match self.state.compare_exchange_weak(state, BUSY, Acquire, Relaxed) {
+                Ok(_) => return state,
+                Err(e) => state = e,
+            }
+        }
+    }
+}
+
+impl<T> Default for MailboxChannel<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for MailboxChannel<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == FULL {
+            unsafe { self.message.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+#[test]
+fn latest_value_wins() {
+    use std::sync::atomic::AtomicUsize;
+
+    static DROPS: [AtomicUsize; 3] = [const { AtomicUsize::new(0) }; 3];
+
+    #[derive(Debug)]
+    struct Counted(usize);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS[self.0].fetch_add(1, Relaxed);
+        }
+    }
+
+    let mailbox = MailboxChannel::new();
+    assert!(mailbox.take().is_none());
+    assert!(mailbox.set(Counted(0)).is_none());
+
+    // The first one comes back, nobody took it.
+    let first = mailbox.set(Counted(1)).unwrap();
+    assert_eq!(first.0, 0);
+    drop(first);
+    assert_eq!(DROPS[0].load(Relaxed), 1);
+
+    let latest = mailbox.take().unwrap();
+    assert_eq!(latest.0, 1);
+    assert!(mailbox.take().is_none());
+    drop(latest);
+
+    // One left in the mailbox is dropped with it.
+    mailbox.set(Counted(2));
+    drop(mailbox);
+    for drops in &DROPS {
+        assert_eq!(drops.load(Relaxed), 1);
+    }
+}
@@ -5,3 +5,7 @@ pub mod s3_single_atomic;
 pub mod s4_types;
 pub mod s5_borrowing;
 pub mod s6_blocking;
+pub mod watch;
+pub mod array_channel;
+pub mod mailbox;
+pub mod broadcast;
@@ -1,10 +1,16 @@
 use std::collections::VecDeque;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
 use std::sync::Condvar;
 use std::sync::Mutex;
 
 pub struct Channel<T> {
     queue: Mutex<VecDeque<T>>,
     item_ready: Condvar,
+    // Set once no more messages would arrive. It's only changed while the
+    // queue is locked, so a receiver that checked it under the lock and went
+    // to wait is guaranteed to get the notify_all from close.
+    closed: AtomicBool,
 }
 
 impl<T> Channel<T> {
@@ -12,6 +18,7 @@ impl<T> Channel<T> {
         Self {
             queue: Mutex::new(VecDeque::new()),
             item_ready: Condvar::new(),
+            closed: AtomicBool::new(false),
         }
     }
 
@@ -22,15 +29,105 @@ impl<T> Channel<T> {
         self.item_ready.notify_one();
     }
 
-    pub fn receive(&self) -> T {
+    // Returns None once the channel is closed and everything sent before that is received.
+    pub fn receive(&self) -> Option<T> {
         // Problem: we are blocking queue for every item, fast bulk processing is not possible.
         // Problem: there could be only one receiver (we would not adress this in that book).
         let mut b = self.queue.lock().unwrap();
         loop {
             if let Some(message) = b.pop_front() {
-                return message;
+                return Some(message);
+            }
+            if self.closed.load(Relaxed) {
+                return None;
             }
             b = self.item_ready.wait(b).unwrap();
         }
     }
+
+    // Tells the receivers that nothing else is coming. Messages that are
+    // already in the queue are still received. All the receivers are woken
+    // up, each of them would need to see the closed flag.
+    pub fn close(&self) {
+        let _queue = self.queue.lock().unwrap();
+        self.closed.store(true, Relaxed);
+        self.item_ready.notify_all();
+    }
+
+    // Never waits on item_ready, so a consumer can do other work in between.
+    pub fn try_receive(&self) -> Option<T> {
+        self.queue.lock().unwrap().pop_front()
+    }
+
+    // These only look at the queue, the lock is held just for that.
+    // By the time the caller sees the result it could be out of date.
+    pub fn peek(&self) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.queue.lock().unwrap().front().cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.lock().unwrap().is_empty()
+    }
+}
+
+#[test]
+fn peek() {
+    let channel = Channel::new();
+    assert!(channel.is_empty());
+    assert_eq!(channel.peek(), None);
+
+    channel.send(String::from("first"));
+    channel.send(String::from("second"));
+    assert_eq!(channel.len(), 2);
+    assert_eq!(channel.peek().as_deref(), Some("first"));
+    assert_eq!(channel.peek().as_deref(), Some("first"));
+
+    assert_eq!(channel.receive().as_deref(), Some("first"));
+    assert_eq!(channel.receive().as_deref(), Some("second"));
+    assert!(channel.is_empty());
+}
+
+#[test]
+fn try_receive() {
+    let channel = Channel::new();
+    assert_eq!(channel.try_receive(), None);
+    channel.send(1);
+    assert_eq!(channel.try_receive(), Some(1));
+    assert_eq!(channel.try_receive(), None);
+}
+
+#[test]
+fn close() {
+    use std::thread;
+    use std::time::Duration;
+
+    let channel = Channel::new();
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            channel.send(1);
+            channel.send(2);
+            channel.close();
+        });
+        assert_eq!(channel.receive(), Some(1));
+        assert_eq!(channel.receive(), Some(2));
+        assert_eq!(channel.receive(), None);
+    });
+
+    // A receiver that is already waiting is woken up by close.
+    let channel = Channel::<i32>::new();
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            channel.close();
+        });
+        assert_eq!(channel.receive(), None);
+    });
 }
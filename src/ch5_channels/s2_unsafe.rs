@@ -1,11 +1,13 @@
 use std::cell::UnsafeCell;
 use std::mem::MaybeUninit;
 use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 
 pub struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
     ready: AtomicBool,
+    // Claimed by the first sender, like in s3_single_atomic.
+    in_use: AtomicBool,
 }
 
 // We tell the compiler that Channel is safe to share between threads,
@@ -40,16 +42,32 @@ impl<T> Channel<T> {
         Self {
             message: UnsafeCell::new(MaybeUninit::uninit()),
             ready: AtomicBool::new(false),
+            in_use: AtomicBool::new(false),
         }
     }
 
     // Safety: Only call this once!
     // Problem: Two threads can call it at the same time and overwrite each other.
     pub unsafe fn send(&self, message: T) {
+        // So that a try_send after this fails.
+        self.in_use.store(true, Relaxed);
         (*self.message.get()).write(message);
         self.ready.store(true, Release);
     }
 
+    // The safe version of send. Only the thread that flips in_use gets to
+    // write the message, everyone else gets their message back. Relaxed is
+    // enough since the flag protects nothing but the right to write, the
+    // message itself is published by the Release store of ready.
+    pub fn try_send(&self, message: T) -> Result<(), T> {
+        if self.in_use.compare_exchange(false, true, Relaxed, Relaxed).is_err() {
+            return Err(message);
+        }
+        unsafe { (*self.message.get()).write(message) };
+        self.ready.store(true, Release);
+        Ok(())
+    }
+
     // Problem: The user must read docs and ensure that this method is called.
     pub fn is_ready(&self) -> bool {
         self.ready.load(Acquire)
@@ -62,3 +80,22 @@ impl<T> Channel<T> {
         (*self.message.get()).assume_init_read()
     }
 }
+
+#[test]
+fn try_send_race() {
+    use std::thread;
+
+    let channel = Channel::new();
+    let results: Vec<Result<(), &str>> = thread::scope(|s| {
+        let a = s.spawn(|| channel.try_send("a"));
+        let b = s.spawn(|| channel.try_send("b"));
+        vec![a.join().unwrap(), b.join().unwrap()]
+    });
+    assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+
+    assert!(channel.is_ready());
+    let received = unsafe { channel.receive() };
+    // The one that failed got its own message back.
+    let rejected = results.iter().find_map(|r| r.err()).unwrap();
+    assert_ne!(received, rejected);
+}
@@ -26,13 +26,24 @@ impl<T> Channel<T> {
     }
 
     pub fn send(&self, message: T) {
+        if self.try_send(message).is_err() {
+            panic!("can't send more than one message!");
+        }
+    }
+
+    // Same as send, but the message is given back instead of a panic.
+    pub fn try_send(&self, message: T) -> Result<(), T> {
+        // Acquire pairs with the Release in reuse. The slot can be a reused
+        // one, and the previous message has to be read out of it before we
+        // start writing a new one there.
         if self.state.compare_exchange(
-            EMPTY, WRITING, Relaxed, Relaxed
+            EMPTY, WRITING, Acquire, Relaxed
         ).is_err() {
-            panic!("can't send more than one message!");
+            return Err(message);
         }
         unsafe { (*self.message.get()).write(message) };
         self.state.store(READY, Release);
+        Ok(())
     }
 
     pub fn is_ready(&self) -> bool {
@@ -40,13 +51,61 @@ impl<T> Channel<T> {
     }
 
     pub fn receive(&self) -> T {
-        if self.state.compare_exchange(
+        match self.try_receive() {
+            Ok(message) => message,
+            Err(_) => panic!("no message available!"),
+        }
+    }
+
+    // Same as receive, but returns an error instead of a panic.
+    pub fn try_receive(&self) -> Result<T, RecvError> {
+        if let Err(state) = self.state.compare_exchange(
             READY, READING, Acquire, Relaxed
-        ).is_err() {
-            panic!("no message available!");
+        ) {
+            return Err(match state {
+                WRITING => RecvError::InProgress,
+                _ => RecvError::Empty,
+            });
         }
-        unsafe { (*self.message.get()).assume_init_read() }
+        Ok(unsafe { (*self.message.get()).assume_init_read() })
+    }
+
+    // After a receive the state stays READING, so the channel is good for
+    // one message only. With exclusive access nobody can be in the middle
+    // of anything, so we can just put it back to EMPTY. A message that was
+    // sent but never received is handed back instead of being dropped.
+    pub fn reset(&mut self) -> Option<T> {
+        let message = if *self.state.get_mut() == READY {
+            Some(unsafe { self.message.get_mut().assume_init_read() })
+        } else {
+            None
+        };
+        *self.state.get_mut() = EMPTY;
+        message
     }
+
+    // Same, but through a shared reference. Only a received (READING) or a
+    // never used (EMPTY) channel goes back to EMPTY, an undelivered message
+    // is never thrown away. Returns whether the channel can be sent to now.
+    //
+    // READING is set before the message is read out, not after. So the
+    // caller must know the receiver is done, e.g. by being the receiver.
+    // Otherwise a new send could overwrite the message while it's still
+    // being read.
+    pub fn reuse(&self) -> bool {
+        match self.state.compare_exchange(READING, EMPTY, Release, Relaxed) {
+            Ok(_) => true,
+            Err(state) => state == EMPTY,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// Nothing was sent yet, or the message was already received.
+    Empty,
+    /// The message is being written right now, try again a bit later.
+    InProgress,
 }
 
 impl<T> Drop for Channel<T> {
@@ -73,3 +132,78 @@ fn main() {
         assert_eq!(channel.receive(), "hello world!");
     });
 }
+
+// The Release store in `send` and the Acquire exchange in `receive` are what
+// make the message bytes visible to the receiving thread. On x86-64 every store
+// is a release store anyway (see the ch7 asm examples), so this test would pass
+// there even with Relaxed everywhere. It is most meaningful on ARM / Apple Silicon
+// where a wrong ordering could let the receiver observe `READY` before all the
+// fields of the message were written.
+//
+// Run it in release mode to make the window between the writes as small as possible:
+// cargo test --release stress_ordering
+#[test]
+fn stress_ordering() {
+    use std::thread;
+
+    // Several fields so that the message is written with more than one store.
+    #[derive(Debug, PartialEq)]
+    struct Payload {
+        a: u64,
+        b: u64,
+        c: u64,
+        d: u64,
+    }
+
+    for i in 0..10_000u64 {
+        let channel = Channel::new();
+        thread::scope(|s| {
+            s.spawn(|| {
+                channel.send(Payload { a: i, b: !i, c: i.wrapping_mul(3), d: i ^ 0xAAAA_AAAA });
+            });
+            // Yield rather than spin so the sender gets scheduled
+            // even when there are fewer cores than threads.
+            while !channel.is_ready() {
+                thread::yield_now();
+            }
+            let p = channel.receive();
+            assert_eq!(p, Payload { a: i, b: !i, c: i.wrapping_mul(3), d: i ^ 0xAAAA_AAAA });
+        });
+    }
+}
+
+#[test]
+fn try_send_receive() {
+    let channel = Channel::new();
+    assert_eq!(channel.try_receive(), Err(RecvError::Empty));
+    assert_eq!(channel.try_send(1), Ok(()));
+    assert_eq!(channel.try_send(2), Err(2));
+    assert_eq!(channel.try_receive(), Ok(1));
+    assert_eq!(channel.try_receive(), Err(RecvError::Empty));
+
+    // Pretend a sender is in the middle of writing.
+    let channel = Channel::<i32>::new();
+    channel.state.store(WRITING, Relaxed);
+    assert_eq!(channel.try_receive(), Err(RecvError::InProgress));
+}
+
+#[test]
+fn reset_and_reuse() {
+    let mut channel = Channel::new();
+    channel.send(String::from("first"));
+    assert_eq!(channel.receive(), "first");
+    assert_eq!(channel.reset(), None);
+
+    channel.send(String::from("second"));
+    assert_eq!(channel.receive(), "second");
+
+    // Through a shared reference this time.
+    assert!(channel.reuse());
+    channel.send(String::from("third"));
+    // Not received yet, so it can't be reused.
+    assert!(!channel.reuse());
+    // But reset gives the message back.
+    assert_eq!(channel.reset(), Some(String::from("third")));
+    assert!(channel.reuse());
+    assert_eq!(channel.try_receive(), Err(RecvError::Empty));
+}
@@ -1,8 +1,15 @@
-use std::cell::UnsafeCell;
+use std::cell::{Cell, UnsafeCell};
+use std::collections::VecDeque;
+use std::future::{Future, IntoFuture};
 use std::mem::MaybeUninit;
-use std::sync::atomic::AtomicBool;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
-use std::sync::Arc;
+use std::sync::{Arc, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use crate::ch9_locks::condvar_2::Condvar;
+use crate::ch9_locks::mutex_3::{Mutex, MutexGuard};
 
 
 pub struct Sender<T> {
@@ -17,6 +24,23 @@ pub struct Receiver<T> {
 struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
     ready: AtomicBool,
+    // Senders can be cloned now, so only one of them is allowed
+    // to write the message. Same trick as in s3_checks.
+    in_use: AtomicBool,
+    // Number of live Sender and Receiver handles. When one side drops to
+    // zero the other side is disconnected: a receiver would never get a
+    // message and a message that is sent would never be received.
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+    // Wakers of the tasks that poll a receiving future, or of the threads
+    // blocked in select. Receivers can be cloned, so there can be several
+    // of them waiting at once, and each one needs its own wake. A single
+    // slot would let the last one to register throw out the others.
+    //
+    // It's a mutex and not an atomic pointer to a box as it used to be,
+    // a list can't be swapped in one go. The wakers are only touched when
+    // a future is polled and when it is woken, not on the plain receive.
+    wakers: Mutex<Vec<Waker>>,
 }
 
 unsafe impl<T> Sync for Channel<T> where T: Send {}
@@ -25,34 +49,283 @@ pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
     let a = Arc::new(Channel {
         message: UnsafeCell::new(MaybeUninit::uninit()),
         ready: AtomicBool::new(false),
+        in_use: AtomicBool::new(false),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+        wakers: Mutex::new(Vec::new()),
     });
     (Sender { channel: a.clone() }, Receiver { channel: a })
 }
 
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvError {
+    /// No message yet, but a sender is still around.
+    Empty,
+    /// All the senders are gone and there is no message,
+    /// so nothing would ever arrive.
+    Disconnected,
+}
+
+// The message that could not be sent is given back.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
 impl<T> Sender<T> {
-    pub fn send(self, message: T) {
+    pub fn send(self, message: T) -> Result<(), SendError<T>> {
+        // The receivers could drop right after this check. Then the
+        // message would be dropped together with the channel, same as
+        // a message that was sent but never received.
+        if self.channel.receivers.load(Relaxed) == 0 {
+            return Err(SendError(message));
+        }
+        if self.channel.in_use.swap(true, Relaxed) {
+            panic!("can't send more than one message!");
+        }
         unsafe { (*self.channel.message.get()).write(message) };
         self.channel.ready.store(true, Release);
+        self.channel.wake();
+        Ok(())
+    }
+
+    // Whether both are handles to the same channel. It compares the
+    // allocations, two channels with the same message are still different.
+    pub fn same_channel(&self, other: &Sender<T>) -> bool {
+        Arc::ptr_eq(&self.channel, &other.channel)
+    }
+
+    // The counts are a snapshot. Another thread can clone or drop
+    // a handle right after we loaded the value, so by the time the
+    // caller looks at the number it could already be stale.
+    pub fn sender_count(&self) -> usize {
+        self.channel.senders.load(Relaxed)
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receivers.load(Relaxed)
+    }
+}
+
+impl<T> Channel<T> {
+    // Nothing panics while the list is locked, the wakers are called
+    // only after it is unlocked. So it can't be poisoned.
+    fn wakers(&self) -> MutexGuard<'_, Vec<Waker>> {
+        self.wakers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // A future is polled again and again with the same waker, that one
+    // is kept only once. A waker that isn't needed anymore (its future
+    // was dropped) stays until the next wake, which is at most one send
+    // and one disconnect away.
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    // Wakes everyone that polls a ReceiveFuture or waits in select. Only one
+    // of them can get the message, the others find out that the sender is
+    // gone (send consumes it) or go back to waiting for the other senders.
+    fn wake(&self) {
+        // Everything changed before this is seen by whoever locks the list
+        // after us. So a receiver that registers later sees it on its
+        // recheck, and one that registered earlier is in the list.
+        let wakers = std::mem::take(&mut *self.wakers());
+        for waker in wakers {
+            waker.wake();
+        }
     }
 }
 
 impl<T> Receiver<T> {
+    // True when receive would not return Empty - either the message
+    // is there, or all the senders are gone and it never will be.
     pub fn is_ready(&self) -> bool {
-        self.channel.ready.load(Relaxed)
+        self.channel.ready.load(Relaxed) || self.channel.senders.load(Relaxed) == 0
     }
 
-    pub fn receive(self) -> T {
-        if !self.channel.ready.swap(false, Acquire) {
-            panic!("no message available!");
-        }
-        unsafe { (*self.channel.message.get()).assume_init_read() }
+    pub fn receive(self) -> Result<T, RecvError> {
+        self.take()
 
         // After receive would be done the self would be dropped, the arc of the channel
         // would decrease and if send already did the same that would be the time when
         // drop would be called on the underlying channel.
     }
+
+    fn take(&self) -> Result<T, RecvError> {
+        if self.channel.ready.swap(false, Acquire) {
+            return Ok(unsafe { (*self.channel.message.get()).assume_init_read() });
+        }
+        // Acquire matches the Release decrement in Sender::drop.
+        if self.channel.senders.load(Acquire) == 0 {
+            // The last sender could have sent the message right before it
+            // was dropped. We'd see that now since the send happened before
+            // the decrement we've just observed.
+            if self.channel.ready.swap(false, Acquire) {
+                return Ok(unsafe { (*self.channel.message.get()).assume_init_read() });
+            }
+            return Err(RecvError::Disconnected);
+        }
+        Err(RecvError::Empty)
+    }
+
+    pub fn same_channel(&self, other: &Receiver<T>) -> bool {
+        Arc::ptr_eq(&self.channel, &other.channel)
+    }
+
+    // The counts are a snapshot. Another thread can clone or drop
+    // a handle right after we loaded the value, so by the time the
+    // caller looks at the number it could already be stale.
+    pub fn sender_count(&self) -> usize {
+        self.channel.senders.load(Relaxed)
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receivers.load(Relaxed)
+    }
+}
+
+// Cloning a Receiver is fine, `ready.swap` in `receive` makes sure
+// that only one of the clones would get the message.
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Relaxed);
+        Receiver { channel: self.channel.clone() }
+    }
+}
+
+// Cloning a Sender is fine since `in_use` guards the message slot.
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Relaxed);
+        Sender { channel: self.channel.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        // Release so that a receiver that sees zero would also see the
+        // message if this sender has sent one.
+        if self.channel.senders.fetch_sub(1, Release) == 1 {
+            // A pending future needs to find out that it is disconnected.
+            self.channel.wake();
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.receivers.fetch_sub(1, Relaxed);
+    }
+}
+
+
+// A minimal Future so the receiver can be `.await`ed with any executor.
+// We don't need a runtime for that, only a way to remember who to wake.
+pub struct ReceiveFuture<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> IntoFuture for Receiver<T> {
+    type Output = Result<T, RecvError>;
+    type IntoFuture = ReceiveFuture<T>;
+
+    fn into_future(self) -> ReceiveFuture<T> {
+        ReceiveFuture { receiver: self }
+    }
+}
+
+impl<T> Future for ReceiveFuture<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.receiver.take() {
+            Err(RecvError::Empty) => {}
+            result => return Poll::Ready(result),
+        }
+
+        self.receiver.channel.register(cx.waker());
+
+        // The message could have arrived after the check above but before the
+        // waker was stored. The sender would have seen no waker then and
+        // nobody would ever wake us. So we check again after registering.
+        // Same goes for the last sender being dropped.
+        //
+        // The list's mutex orders us with Channel::wake. Either the sender took
+        // the list after we registered, or we registered after it took the list
+        // and then we see its change here.
+        match self.receiver.take() {
+            Err(RecvError::Empty) => Poll::Pending,
+            result => Poll::Ready(result),
+        }
+    }
+}
+
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+// Wakes a thread blocked in select by unparking it.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
 }
 
+// Blocks until one of the two receivers gets its message.
+//
+// It's the same dance as in ReceiveFuture::poll, only done on both channels
+// with a waker that unparks the current thread. A send on either channel
+// takes our waker out of the list and wakes us up.
+//
+// If both messages are there, only one of them can be returned. Always
+// checking `a` first would make it win every tie, so the order flips on
+// every call. The flag is per thread, a shared one would be one more
+// atomic that all the selecting threads fight over. The message that
+// lost stays where it is and the next select returns it.
+//
+// A channel whose sender is gone is skipped. Panics if both of them are gone,
+// since we would block forever otherwise.
+pub fn select<A, B>(a: &Receiver<A>, b: &Receiver<B>) -> Either<A, B> {
+    thread_local! {
+        static A_FIRST: Cell<bool> = const { Cell::new(true) };
+    }
+    let a_first = A_FIRST.with(|flag| flag.replace(!flag.get()));
+
+    let take_a = || a.take().map(Either::Left);
+    let take_b = || b.take().map(Either::Right);
+    let order: [&dyn Fn() -> Result<Either<A, B>, RecvError>; 2] =
+        if a_first { [&take_a, &take_b] } else { [&take_b, &take_a] };
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut registered = false;
+    loop {
+        let mut disconnected = 0;
+        for take in order {
+            match take() {
+                Ok(message) => return message,
+                Err(RecvError::Disconnected) => disconnected += 1,
+                Err(RecvError::Empty) => {}
+            }
+        }
+        if disconnected == 2 {
+            panic!("both channels are disconnected");
+        }
+
+        if registered {
+            // Spurious unparks are possible, the loop checks again.
+            thread::park();
+        } else {
+            // Same as in poll: register, then check again before sleeping.
+            a.channel.register(&waker);
+            b.channel.register(&waker);
+            registered = true;
+        }
+    }
+}
 
 impl<T> Drop for Channel<T> {
     // We don't use Atomic API here since drop can only happen from a thread that
@@ -72,6 +345,233 @@ impl<T> Drop for Channel<T> {
     }
 }
 
+// The channel above can carry only one message. This one is a queue of
+// up to `capacity` messages with backpressure: a sender blocks while the
+// queue is full and a receiver blocks while it is empty.
+//
+// It's built on our own Mutex and Condvar from ch9 instead of atomics.
+// Any number of senders and receivers is fine here since every access
+// to the queue goes through the mutex.
+pub struct BoundedSender<T> {
+    channel: Arc<BoundedChannel<T>>,
+}
+
+pub struct BoundedReceiver<T> {
+    channel: Arc<BoundedChannel<T>>,
+}
+
+struct BoundedChannel<T> {
+    // VecDeque is a ring buffer. It is allocated for the full capacity
+    // upfront and never grows since we never push past the capacity.
+    queue: Mutex<VecDeque<T>>,
+    capacity: usize,
+    not_empty: Condvar,
+    not_full: Condvar,
+    // For flush. A separate condvar, so that a receive that frees a single
+    // slot doesn't spend its notify_one on a flushing sender instead of
+    // one that is blocked in send.
+    emptied: Condvar,
+    // The last Sender and the last Receiver are dropped while holding the
+    // mutex. That makes the checks in send and receive (that are done under
+    // the mutex too) race free.
+    senders: AtomicUsize,
+    receivers: AtomicUsize,
+}
+
+pub fn bounded<T>(capacity: usize) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    assert!(capacity > 0, "capacity must be at least 1");
+    let a = Arc::new(BoundedChannel {
+        queue: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity,
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        emptied: Condvar::new(),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1),
+    });
+    (BoundedSender { channel: a.clone() }, BoundedReceiver { channel: a })
+}
+
+impl<T> BoundedSender<T> {
+    // Blocks while the queue is full. Gives the message back if there
+    // is no receiver left, otherwise we would block forever.
+    pub fn send(&self, message: T) -> Result<(), T> {
+        let mut queue = self.channel.queue.lock().unwrap();
+        loop {
+            if self.channel.receivers.load(Relaxed) == 0 {
+                return Err(message);
+            }
+            if queue.len() < self.channel.capacity {
+                break;
+            }
+            queue = self.channel.not_full.wait(queue).unwrap();
+        }
+        queue.push_back(message);
+        drop(queue);
+        self.channel.not_empty.notify_one();
+        Ok(())
+    }
+
+    // Blocks until the receivers have taken everything that is in the
+    // queue, e.g. before tearing things down. Returns right away if no
+    // receiver is left, then nobody would ever empty it.
+    //
+    // Other senders can keep sending, so the queue can be non empty
+    // again by the time this returns.
+    pub fn flush(&self) {
+        let mut queue = self.channel.queue.lock().unwrap();
+        while !queue.is_empty() && self.channel.receivers.load(Relaxed) > 0 {
+            queue = self.channel.emptied.wait(queue).unwrap();
+        }
+    }
+
+    pub fn sender_count(&self) -> usize {
+        self.channel.senders.load(Relaxed)
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receivers.load(Relaxed)
+    }
+}
+
+impl<T> BoundedReceiver<T> {
+    // Blocks while the queue is empty. Returns None once the queue is
+    // empty and all the senders are gone since nothing would arrive then.
+    pub fn receive(&self) -> Option<T> {
+        self.receive_with_len().map(|(message, _)| message)
+    }
+
+    // Same as receive, plus how many messages are left in the queue right
+    // after ours was taken out. A consumer that sees a long queue can take
+    // more at once, e.g. with drain.
+    //
+    // The length is read under the same lock as the pop, but other threads
+    // can change it as soon as we let go of the lock.
+    pub fn receive_with_len(&self) -> Option<(T, usize)> {
+        let mut queue = self.channel.queue.lock().unwrap();
+        loop {
+            if let Some(message) = queue.pop_front() {
+                let len = queue.len();
+                drop(queue);
+                self.channel.not_full.notify_one();
+                if len == 0 {
+                    self.channel.emptied.notify_all();
+                }
+                return Some((message, len));
+            }
+            if self.channel.senders.load(Relaxed) == 0 {
+                return None;
+            }
+            queue = self.channel.not_empty.wait(queue).unwrap();
+        }
+    }
+
+    // Takes everything that is in the queue right now, without waiting
+    // for more. Can be empty.
+    pub fn drain(&self) -> Vec<T> {
+        let mut queue = self.channel.queue.lock().unwrap();
+        let messages: Vec<T> = queue.drain(..).collect();
+        drop(queue);
+        if !messages.is_empty() {
+            // Many slots could have been freed at once.
+            self.channel.not_full.notify_all();
+            self.channel.emptied.notify_all();
+        }
+        messages
+    }
+
+    // Waits for all the senders to finish, and gives back everything they
+    // sent. Same as collecting the iterator below, receive already returns
+    // None only when the queue is empty and the last sender is gone.
+    //
+    // Don't call it while holding a sender yourself, it'd wait forever.
+    pub fn recv_all(&self) -> Vec<T> {
+        self.into_iter().collect()
+    }
+
+    // Counted separately from the Arc. Its strong count minus one would
+    // only work with a single receiver, the receiver clones hold it too.
+    pub fn sender_count(&self) -> usize {
+        self.channel.senders.load(Relaxed)
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receivers.load(Relaxed)
+    }
+}
+
+// Iterating blocks on the condvar between messages and ends when the
+// channel is disconnected. `for message in receiver` works through the
+// blanket IntoIterator impl that every Iterator gets.
+impl<T> Iterator for BoundedReceiver<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receive()
+    }
+}
+
+// And this is for `for message in &receiver` that keeps the receiver around.
+impl<'a, T> IntoIterator for &'a BoundedReceiver<T> {
+    type Item = T;
+    type IntoIter = BoundedIter<'a, T>;
+
+    fn into_iter(self) -> BoundedIter<'a, T> {
+        BoundedIter { receiver: self }
+    }
+}
+
+pub struct BoundedIter<'a, T> {
+    receiver: &'a BoundedReceiver<T>,
+}
+
+impl<T> Iterator for BoundedIter<'_, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.receiver.receive()
+    }
+}
+
+// Every clone is a producer of its own. The receiver is disconnected
+// only once the last of them is dropped.
+impl<T> Clone for BoundedSender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Relaxed);
+        BoundedSender { channel: self.channel.clone() }
+    }
+}
+
+impl<T> Clone for BoundedReceiver<T> {
+    fn clone(&self) -> Self {
+        self.channel.receivers.fetch_add(1, Relaxed);
+        BoundedReceiver { channel: self.channel.clone() }
+    }
+}
+
+impl<T> Drop for BoundedSender<T> {
+    fn drop(&mut self) {
+        let queue = self.channel.queue.lock().unwrap();
+        if self.channel.senders.fetch_sub(1, Relaxed) == 1 {
+            drop(queue);
+            // Blocked receivers need to find out that nothing is coming.
+            self.channel.not_empty.notify_all();
+        }
+    }
+}
+
+impl<T> Drop for BoundedReceiver<T> {
+    fn drop(&mut self) {
+        let queue = self.channel.queue.lock().unwrap();
+        if self.channel.receivers.fetch_sub(1, Relaxed) == 1 {
+            drop(queue);
+            // Blocked senders need to find out that nobody is listening.
+            self.channel.not_full.notify_all();
+            self.channel.emptied.notify_all();
+        }
+    }
+}
+
 #[test]
 fn main() {
     use std::thread;
@@ -79,7 +579,7 @@ fn main() {
         let (sender, receiver) = channel();
         let t = thread::current();
         s.spawn(move || {
-            sender.send("hello world!");
+            sender.send("hello world!").unwrap();
             t.unpark();
         });
         while !receiver.is_ready() {
@@ -87,6 +587,338 @@ fn main() {
             // For now we manually park the thread.
             thread::park();
         }
-        assert_eq!(receiver.receive(), "hello world!");
+        assert_eq!(receiver.receive(), Ok("hello world!"));
+    });
+}
+
+#[test]
+fn endpoint_counts() {
+    let (sender, receiver) = channel::<i32>();
+    assert_eq!((sender.sender_count(), sender.receiver_count()), (1, 1));
+
+    let sender2 = sender.clone();
+    let sender3 = sender.clone();
+    let receiver2 = receiver.clone();
+    assert_eq!((receiver.sender_count(), receiver.receiver_count()), (3, 2));
+
+    drop(sender2);
+    drop(receiver);
+    assert_eq!((receiver2.sender_count(), receiver2.receiver_count()), (2, 1));
+
+    // Sending consumes the sender, so it is gone afterwards too.
+    sender3.send(1).unwrap();
+    drop(receiver2);
+    assert_eq!((sender.sender_count(), sender.receiver_count()), (1, 0));
+}
+
+#[test]
+fn same_channel() {
+    let (sender, receiver) = channel::<i32>();
+    let (other_sender, other_receiver) = channel::<i32>();
+
+    let clone = sender.clone();
+    assert!(sender.same_channel(&clone));
+    assert!(clone.same_channel(&sender));
+    assert!(!sender.same_channel(&other_sender));
+
+    assert!(receiver.same_channel(&receiver.clone()));
+    assert!(!receiver.same_channel(&other_receiver));
+}
+
+// The simplest executor there is: poll, and park until woken.
+#[cfg(test)]
+fn block_on<F: IntoFuture>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future.into_future());
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(value) => return value,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[test]
+fn receive_future() {
+    use std::time::Duration;
+
+    let (sender, receiver) = channel();
+    thread::scope(|s| {
+        s.spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            sender.send(String::from("hello world!")).unwrap();
+        });
+        assert_eq!(block_on(receiver).unwrap(), "hello world!");
+    });
+
+    // Message that is already there is returned on the first poll.
+    let (sender, receiver) = channel();
+    sender.send(42).unwrap();
+    assert_eq!(block_on(receiver), Ok(42));
+
+    // Dropping the sender wakes the future up too.
+    let (sender, receiver) = channel::<i32>();
+    thread::scope(|s| {
+        s.spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(sender);
+        });
+        assert_eq!(block_on(receiver), Err(RecvError::Disconnected));
+    });
+}
+
+#[test]
+fn receive_future_clones() {
+    let (sender, receiver) = channel();
+    thread::scope(|s| {
+        let waiting = [
+            s.spawn({
+                let receiver = receiver.clone();
+                move || block_on(receiver)
+            }),
+            s.spawn(move || block_on(receiver)),
+        ];
+        // Both are polled and waiting before anything is sent.
+        while sender.channel.wakers().len() < 2 {
+            thread::yield_now();
+        }
+        sender.send(42).unwrap();
+
+        // One gets the message. The other one is woken too, and finds
+        // out that the sender is gone, instead of waiting forever.
+        let mut results = waiting.map(|t| t.join().unwrap());
+        results.sort_by_key(|r| r.is_err());
+        assert_eq!(results, [Ok(42), Err(RecvError::Disconnected)]);
+    });
+}
+
+#[test]
+fn bounded_channel() {
+    use std::thread;
+
+    let (sender, receiver) = bounded(2);
+    thread::scope(|s| {
+        for _ in 0..2 {
+            let sender = sender.clone();
+            s.spawn(move || {
+                for i in 0..1000 {
+                    sender.send(i).unwrap();
+                }
+            });
+        }
+        let mut sum = 0;
+        for _ in 0..2000 {
+            sum += receiver.receive().unwrap();
+        }
+        assert_eq!(sum, 2 * (0..1000).sum::<i32>());
+    });
+    assert_eq!(receiver.channel.queue.lock().unwrap().len(), 0);
+
+    // A sender blocked on a full queue is released when the receiver goes away.
+    sender.send(1).unwrap();
+    sender.send(2).unwrap();
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(std::time::Duration::from_millis(50));
+            drop(receiver);
+        });
+        assert_eq!(sender.send(3), Err(3));
+    });
+    assert_eq!(sender.send(4), Err(4));
+}
+
+#[test]
+fn disconnected() {
+    // Sender goes away without sending.
+    let (sender, receiver) = channel::<i32>();
+    let receiver2 = receiver.clone();
+    assert!(!receiver.is_ready());
+    assert_eq!(receiver.take(), Err(RecvError::Empty));
+    drop(sender);
+    assert!(receiver.is_ready());
+    assert_eq!(receiver.receive(), Err(RecvError::Disconnected));
+    assert_eq!(receiver2.receive(), Err(RecvError::Disconnected));
+
+    // A message that was sent before the sender dropped is still there.
+    let (sender, receiver) = channel();
+    sender.send(1).unwrap();
+    assert_eq!(receiver.receive(), Ok(1));
+
+    // Receiver goes away first, the message comes back.
+    let (sender, receiver) = channel();
+    drop(receiver);
+    assert_eq!(sender.send(String::from("hi")), Err(SendError(String::from("hi"))));
+}
+
+#[test]
+fn bounded_iterator() {
+    use std::thread;
+
+    let (sender, receiver) = bounded(4);
+    thread::scope(|s| {
+        s.spawn(move || {
+            for i in 0..100 {
+                sender.send(i).unwrap();
+            }
+        });
+        let received: Vec<i32> = receiver.collect();
+        assert_eq!(received, (0..100).collect::<Vec<_>>());
+    });
+
+    let (sender, receiver) = bounded(4);
+    sender.send(1).unwrap();
+    sender.send(2).unwrap();
+    drop(sender);
+    let mut received = Vec::new();
+    for message in &receiver {
+        received.push(message);
+    }
+    assert_eq!(received, [1, 2]);
+    assert_eq!(receiver.receive(), None);
+}
+
+#[test]
+fn bounded_many_producers() {
+    use std::thread;
+
+    let (sender, receiver) = bounded(16);
+    thread::scope(|s| {
+        for p in 0..4 {
+            let sender = sender.clone();
+            s.spawn(move || {
+                for i in 0..1000 {
+                    sender.send(p * 1000 + i).unwrap();
+                }
+            });
+        }
+        // The original sender is still alive, so after all the producers
+        // are done the receiver would still wait for more.
+        let mut received: Vec<i32> = (0..4000).map(|_| receiver.receive().unwrap()).collect();
+        received.sort();
+        assert_eq!(received, (0..4000).collect::<Vec<_>>());
     });
+    assert_eq!(sender.sender_count(), 1);
+    drop(sender);
+    assert_eq!(receiver.receive(), None);
+}
+
+#[test]
+fn select_two() {
+    use std::time::Duration;
+
+    let (number_sender, numbers) = channel::<i32>();
+    let (text_sender, texts) = channel::<&str>();
+    thread::scope(|s| {
+        s.spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            number_sender.send(42).unwrap();
+        });
+        s.spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            text_sender.send("hi").unwrap();
+        });
+
+        let mut number = None;
+        let mut text = None;
+        for _ in 0..2 {
+            match select(&numbers, &texts) {
+                Either::Left(n) => number = Some(n),
+                Either::Right(t) => text = Some(t),
+            }
+        }
+        assert_eq!(number, Some(42));
+        assert_eq!(text, Some("hi"));
+    });
+}
+
+#[test]
+fn select_takes_turns() {
+    // Both messages are always there, so every select is a tie.
+    let mut lefts = 0;
+    for _ in 0..10 {
+        let (a_sender, a) = channel();
+        let (b_sender, b) = channel();
+        a_sender.send(1).unwrap();
+        b_sender.send(2).unwrap();
+        if let Either::Left(_) = select(&a, &b) {
+            lefts += 1;
+        }
+    }
+    assert_eq!(lefts, 5);
+}
+
+#[test]
+fn bounded_flush_and_drain() {
+    use std::time::Duration;
+
+    let (sender, receiver) = bounded(3);
+    for i in 0..3 {
+        sender.send(i).unwrap();
+    }
+    assert_eq!(receiver.drain(), [0, 1, 2]);
+    assert_eq!(receiver.drain(), []);
+    // Nothing to wait for.
+    sender.flush();
+
+    for i in 0..3 {
+        sender.send(i).unwrap();
+    }
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            for i in 0..3 {
+                assert_eq!(receiver.receive(), Some(i));
+            }
+        });
+        // The consumer only starts after a while, the queue is full now.
+        sender.flush();
+        assert!(receiver.channel.queue.lock().unwrap().is_empty());
+    });
+
+    // With no receiver left there is nobody to flush to.
+    sender.send(7).unwrap();
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            drop(receiver);
+        });
+        sender.flush();
+    });
+}
+
+#[test]
+fn bounded_recv_all() {
+    let (sender, receiver) = bounded(4);
+    thread::scope(|s| {
+        for p in 0..3 {
+            let sender = sender.clone();
+            s.spawn(move || {
+                for i in 0..10 {
+                    sender.send(p * 10 + i).unwrap();
+                }
+            });
+        }
+        // The producers have their own clones.
+        drop(sender);
+        let mut received = receiver.recv_all();
+        received.sort();
+        assert_eq!(received, (0..30).collect::<Vec<_>>());
+        assert_eq!(receiver.sender_count(), 0);
+    });
+}
+
+#[test]
+fn bounded_receive_with_len() {
+    let (sender, receiver) = bounded(5);
+    for i in 0..5 {
+        sender.send(i).unwrap();
+    }
+    assert_eq!(receiver.receive_with_len(), Some((0, 4)));
+    assert_eq!(receiver.receive_with_len(), Some((1, 3)));
+    assert_eq!(receiver.drain(), [2, 3, 4]);
+    sender.send(5).unwrap();
+    assert_eq!(receiver.receive_with_len(), Some((5, 0)));
+    drop(sender);
+    assert_eq!(receiver.receive_with_len(), None);
 }
@@ -1,5 +1,6 @@
 use std::cell::UnsafeCell;
 use std::mem::MaybeUninit;
+use std::ops::Deref;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 
@@ -54,7 +55,7 @@ impl<T> Sender<'_, T> {
 }
 
 // Not possible to elide the lifetime parameter, it is important part of the signature
-impl<T> Receiver<'_, T> {
+impl<'a, T> Receiver<'a, T> {
     pub fn is_ready(&self) -> bool {
         self.channel.ready.load(Relaxed)
     }
@@ -65,6 +66,59 @@ impl<T> Receiver<'_, T> {
         }
         unsafe { (*self.channel.message.get()).assume_init_read() }
     }
+
+    // For large messages that we only need to look at: the message stays
+    // where the sender wrote it and the guard borrows it from there.
+    //
+    // Takes the receiver by value, same as receive. With &self a second
+    // call would make a second guard for the same message, and both of
+    // them would drop it. Now there is one guard at most, and once it's
+    // gone there is no receiver left to wait for a message that won't come.
+    //
+    // There is no thread to unpark in this version of the channel (see s6
+    // for that), so we wait for the message by yielding in a loop.
+    pub fn receive_ref(self) -> MessageRef<'a, T> {
+        while !self.channel.ready.load(Acquire) {
+            std::thread::yield_now();
+        }
+        MessageRef { channel: self.channel }
+    }
+}
+
+// ready stays set while the guard is alive, the message is still in the
+// channel after all. The Receiver is used up to make the guard, so nothing
+// else can move the message out from under it, or make another guard:
+//
+/// ```compile_fail,E0382
+/// # use rust_atomics_and_locks::ch5_channels::s5_borrowing::Channel;
+/// let mut channel = Channel::new();
+/// let (sender, receiver) = channel.split();
+/// sender.send(1);
+/// let a = receiver.receive_ref();
+/// let b = receiver.receive_ref(); // receiver was moved
+/// ```
+pub struct MessageRef<'a, T> {
+    channel: &'a Channel<T>,
+}
+
+impl<T> Deref for MessageRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: ready is set, so the message is written and nobody
+        // is going to touch it until the guard is gone.
+        unsafe { (*self.channel.message.get()).assume_init_ref() }
+    }
+}
+
+impl<T> Drop for MessageRef<'_, T> {
+    fn drop(&mut self) {
+        // The message is dropped either here or by Channel::drop, it's the
+        // flag that decides. It's cleared first, so even if T's drop panics
+        // the channel would not drop the message a second time.
+        self.channel.ready.store(false, Relaxed);
+        unsafe { (*self.channel.message.get()).assume_init_drop() }
+    }
 }
 
 impl<T> Drop for Channel<T> {
@@ -103,3 +157,33 @@ fn main() {
         assert_eq!(receiver.receive(), "hello world!");
     });
 }
+
+#[test]
+fn receive_ref() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Message(String);
+
+    impl Drop for Message {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let mut channel = Channel::new();
+    thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        s.spawn(move || sender.send(Message(String::from("a large message"))));
+        let message = receiver.receive_ref();
+        assert_eq!(message.0, "a large message");
+        assert_eq!(NUM_DROPS.load(Relaxed), 0);
+        drop(message);
+        assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    });
+    // The channel doesn't drop it again.
+    drop(channel);
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+}
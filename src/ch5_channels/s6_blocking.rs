@@ -5,10 +5,13 @@ use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::{Acquire, Release};
 use std::thread;
 use std::thread::Thread;
+use std::time::{Duration, Instant};
 
 pub struct Channel<T> {
     message: UnsafeCell<MaybeUninit<T>>,
     ready: AtomicBool,
+    // Set when the sender is gone, whether it has sent something or not.
+    disconnected: AtomicBool,
 }
 
 unsafe impl<T> Sync for Channel<T> where T: Send {}
@@ -28,6 +31,7 @@ impl<T> Channel<T> {
         Self {
             message: UnsafeCell::new(MaybeUninit::uninit()),
             ready: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
         }
     }
 
@@ -54,6 +58,17 @@ impl<T> Channel<T> {
             }
         )
     }
+
+    // Gets back a message that was sent but never received. We own the
+    // channel, so the sender and the receiver are gone and no atomics are
+    // needed. The flag is cleared before the channel is dropped, so Drop
+    // doesn't drop the message that is now the caller's.
+    pub fn try_into_inner(mut self) -> Option<T> {
+        if !std::mem::replace(self.ready.get_mut(), false) {
+            return None;
+        }
+        Some(unsafe { self.message.get_mut().assume_init_read() })
+    }
 }
 
 impl<T> Sender<'_, T> {
@@ -64,6 +79,21 @@ impl<T> Sender<'_, T> {
     }
 }
 
+impl<T> Drop for Sender<'_, T> {
+    fn drop(&mut self) {
+        // Runs after send as well, since send consumes the sender.
+        // Release makes the message visible to whoever sees the flag.
+        self.channel.disconnected.store(true, Release);
+        self.receiving_thread.unpark();
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RecvTimeoutError {
+    Timeout,
+    Disconnected,
+}
+
 impl<T> Receiver<'_, T> {
     pub fn receive(self) -> T {
         // The cycle is still needed here since a thread may get CPU time
@@ -76,6 +106,30 @@ impl<T> Receiver<'_, T> {
         }
         unsafe { (*self.channel.message.get()).assume_init_read() }
     }
+
+    pub fn receive_timeout(self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        // park_timeout can return early - on a spurious wakeup or an unpark
+        // token that was left over. So we wait against a deadline and
+        // park again only for the time that is left.
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.channel.ready.swap(false, Acquire) {
+                return Ok(unsafe { (*self.channel.message.get()).assume_init_read() });
+            }
+            if self.channel.disconnected.load(Acquire) {
+                // The sender could have sent right before it was dropped.
+                if self.channel.ready.swap(false, Acquire) {
+                    return Ok(unsafe { (*self.channel.message.get()).assume_init_read() });
+                }
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            thread::park_timeout(deadline - now);
+        }
+    }
 }
 
 impl<T> Drop for Channel<T> {
@@ -97,3 +151,64 @@ fn main() {
         assert_eq!(receiver.receive(), "hello world!");
     });
 }
+
+#[test]
+fn receive_timeout() {
+    let mut channel = Channel::<i32>::new();
+    let (sender, receiver) = channel.split();
+    let start = Instant::now();
+    assert_eq!(
+        receiver.receive_timeout(Duration::from_millis(100)),
+        Err(RecvTimeoutError::Timeout)
+    );
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(100));
+    assert!(elapsed < Duration::from_secs(5));
+    drop(sender);
+
+    thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        s.spawn(move || drop(sender));
+        assert_eq!(
+            receiver.receive_timeout(Duration::from_secs(10)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    });
+
+    thread::scope(|s| {
+        let (sender, receiver) = channel.split();
+        s.spawn(move || sender.send(7));
+        assert_eq!(receiver.receive_timeout(Duration::from_secs(10)), Ok(7));
+    });
+}
+
+#[test]
+fn try_into_inner() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let mut channel = Channel::new();
+    // The receiver never receives.
+    let (sender, _receiver) = channel.split();
+    sender.send(DetectDrop);
+
+    let message = channel.try_into_inner();
+    assert!(message.is_some());
+    assert_eq!(NUM_DROPS.load(Relaxed), 0);
+    drop(message);
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+
+    // Nothing sent, nothing to get back.
+    assert!(Channel::<DetectDrop>::new().try_into_inner().is_none());
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+}
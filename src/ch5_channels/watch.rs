@@ -0,0 +1,105 @@
+use std::sync::Arc;
+use crate::ch9_locks::condvar_2::Condvar;
+use crate::ch9_locks::mutex_3::Mutex;
+
+// A "watch" channel holds just one value - the latest one. Sending
+// overwrites whatever was there, so a slow receiver never has a backlog
+// to chew through and always skips straight to the freshest value.
+//
+// Every send bumps the version. The receiver can tell how many updates
+// it missed by looking at the difference between two versions.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    // The value together with its version, so that they are always
+    // updated and read together.
+    latest: Mutex<(T, u64)>,
+    changed: Condvar,
+}
+
+pub fn channel<T>(initial: T) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        latest: Mutex::new((initial, 0)),
+        changed: Condvar::new(),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+impl<T> Sender<T> {
+    // Replaces the value and returns its version. Versions start at 0
+    // for the initial value and grow by one with every send.
+    pub fn send_coalesced(&self, value: T) -> u64 {
+        let mut latest = self.shared.latest.lock().unwrap();
+        latest.1 += 1;
+        let version = latest.1;
+        latest.0 = value;
+        drop(latest);
+        self.shared.changed.notify_all();
+        version
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    // Doesn't block. Before anything was sent that is the
+    // initial value with version 0.
+    pub fn latest(&self) -> (T, u64) {
+        let latest = self.shared.latest.lock().unwrap();
+        (latest.0.clone(), latest.1)
+    }
+
+    // Blocks until there is a value newer than `last_version`
+    // and returns it with its version.
+    pub fn wait_new(&self, last_version: u64) -> (T, u64) {
+        let mut latest = self.shared.latest.lock().unwrap();
+        while latest.1 <= last_version {
+            latest = self.shared.changed.wait(latest).unwrap();
+        }
+        (latest.0.clone(), latest.1)
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Receiver { shared: self.shared.clone() }
+    }
+}
+
+#[test]
+fn coalesced_updates() {
+    use std::thread;
+    use std::time::Duration;
+
+    let (sender, receiver) = channel(0u64);
+    assert_eq!(receiver.latest(), (0, 0));
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            for i in 1..=100 {
+                assert_eq!(sender.send_coalesced(i), i);
+                if i % 10 == 0 {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        });
+
+        let mut version = 0;
+        let mut observed = 0;
+        while version < 100 {
+            let (value, new_version) = receiver.wait_new(version);
+            assert!(new_version > version);
+            // The value we got is the one that was sent with that version.
+            assert_eq!(value, new_version);
+            version = new_version;
+            observed += 1;
+            // Slow consumer.
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(observed < 100, "observed = {}", observed);
+    });
+}
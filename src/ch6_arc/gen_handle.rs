@@ -0,0 +1,218 @@
+use std::cell::UnsafeCell;
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::sync::atomic::fence;
+use std::sync::atomic::{AtomicU64, AtomicUsize};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// A variation of the Arc from s2_weak, where the allocation of a dead Arc
+// can be handed out again for a new value (like a slot in a pool). A plain
+// Weak can't tell the old value from the new one - it would happily upgrade
+// to whatever lives in the allocation now. WeakGen remembers the generation
+// it was created at and refuses to upgrade once the generation has moved on.
+struct ArcData<T> {
+    /// Odd while there is data, even once it was dropped.
+    /// Dropping the data and reusing the allocation both move it forward.
+    generation: AtomicU64,
+
+    /// Number of GenArc`s.
+    data_ref_count: AtomicUsize,
+
+    /// Number of GenArc`s and WeakGen`s combined.
+    alloc_ref_count: AtomicUsize,
+
+    /// The data, `None` if there's only weak handles left.
+    data: UnsafeCell<Option<T>>,
+}
+
+pub struct GenArc<T> {
+    ptr: NonNull<ArcData<T>>,
+}
+
+pub struct WeakGen<T> {
+    ptr: NonNull<ArcData<T>>,
+    generation: u64,
+}
+
+unsafe impl<T: Sync + Send> Send for GenArc<T> {}
+unsafe impl<T: Sync + Send> Sync for GenArc<T> {}
+unsafe impl<T: Sync + Send> Send for WeakGen<T> {}
+unsafe impl<T: Sync + Send> Sync for WeakGen<T> {}
+
+fn release_alloc<T>(ptr: NonNull<ArcData<T>>) {
+    if unsafe { ptr.as_ref() }.alloc_ref_count.fetch_sub(1, Release) == 1 {
+        fence(Acquire);
+        unsafe { drop(Box::from_raw(ptr.as_ptr())) };
+    }
+}
+
+impl<T> GenArc<T> {
+    pub fn new(data: T) -> GenArc<T> {
+        GenArc {
+            ptr: NonNull::from(Box::leak(Box::new(ArcData {
+                generation: AtomicU64::new(1),
+                data_ref_count: AtomicUsize::new(1),
+                alloc_ref_count: AtomicUsize::new(1),
+                data: UnsafeCell::new(Some(data)),
+            }))),
+        }
+    }
+
+    fn data(&self) -> &ArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn downgrade(arc: &Self) -> WeakGen<T> {
+        let data = arc.data();
+        if data.alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        WeakGen {
+            ptr: arc.ptr,
+            // Can't change while we hold a GenArc.
+            generation: data.generation.load(Relaxed),
+        }
+    }
+}
+
+impl<T> WeakGen<T> {
+    fn data(&self) -> &ArcData<T> {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn get(&self) -> Option<GenArc<T>> {
+        let data = self.data();
+        let mut n = data.data_ref_count.load(Relaxed);
+        loop {
+            if n == 0 || data.generation.load(Relaxed) != self.generation {
+                return None;
+            }
+            assert!(n <= usize::MAX / 2);
+            if let Err(e) = data.data_ref_count.compare_exchange_weak(n, n + 1, Acquire, Relaxed) {
+                n = e;
+                continue;
+            }
+            // The allocation is alive since we are holding a weak handle.
+            data.alloc_ref_count.fetch_add(1, Relaxed);
+            let arc = GenArc { ptr: self.ptr };
+
+            // Here is the ABA problem this type is about. Between the load of
+            // `n` and the compare_exchange the old data could have been dropped
+            // and the allocation reused, bringing data_ref_count back to `n`.
+            // Then we've just got a reference to somebody else's value. The
+            // Acquire above syncs with the Release store in `reuse`, so the
+            // new generation is visible to us here.
+            if data.generation.load(Relaxed) != self.generation {
+                // This is a regular drop, it can even be the last one.
+                drop(arc);
+                return None;
+            }
+            return Some(arc);
+        }
+    }
+
+    // Puts a new value into the allocation once the old one was dropped.
+    // Returns the value back if the data is still alive (or somebody
+    // else was faster to reuse the allocation).
+    pub fn reuse(&self, value: T) -> Result<GenArc<T>, T> {
+        let data = self.data();
+        let generation = data.generation.load(Relaxed);
+        if generation % 2 == 1 {
+            return Err(value);
+        }
+        // Only one thread can move the generation from even to odd. Acquire
+        // matches the Release increment in GenArc::drop, so the old value
+        // is fully dropped by now.
+        if data.generation.compare_exchange(generation, generation + 1, Acquire, Relaxed).is_err() {
+            return Err(value);
+        }
+        // Safety: The generation was even, so data_ref_count is zero and
+        // no WeakGen can upgrade until we store 1 into it below.
+        unsafe { *data.data.get() = Some(value) };
+        data.alloc_ref_count.fetch_add(1, Relaxed);
+        data.data_ref_count.store(1, Release);
+        Ok(GenArc { ptr: self.ptr })
+    }
+}
+
+impl<T> Deref for GenArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // Safety: Since there's a GenArc to the data,
+        // the data exists and may be shared.
+        unsafe { (*self.data().data.get()).as_ref().unwrap() }
+    }
+}
+
+impl<T> Clone for GenArc<T> {
+    fn clone(&self) -> Self {
+        let data = self.data();
+        if data.alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        if data.data_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        GenArc { ptr: self.ptr }
+    }
+}
+
+impl<T> Clone for WeakGen<T> {
+    fn clone(&self) -> Self {
+        if self.data().alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
+            std::process::abort();
+        }
+        WeakGen { ptr: self.ptr, generation: self.generation }
+    }
+}
+
+impl<T> Drop for GenArc<T> {
+    fn drop(&mut self) {
+        let data = self.data();
+        if data.data_ref_count.fetch_sub(1, Release) == 1 {
+            fence(Acquire);
+            // Safety: The data reference counter is zero,
+            // so nothing will access it.
+            unsafe { *data.data.get() = None };
+            // Even generation - the allocation can be reused now.
+            data.generation.fetch_add(1, Release);
+        }
+        release_alloc(self.ptr);
+    }
+}
+
+impl<T> Drop for WeakGen<T> {
+    fn drop(&mut self) {
+        release_alloc(self.ptr);
+    }
+}
+
+#[test]
+fn stale_handle() {
+    let a = GenArc::new(String::from("first"));
+    let old = GenArc::downgrade(&a);
+    let slot = GenArc::downgrade(&a);
+    assert_eq!(*old.get().unwrap(), "first");
+
+    // Can't reuse the allocation while the data is alive.
+    let Err(value) = slot.reuse(String::from("second")) else {
+        panic!("reused a live allocation");
+    };
+
+    drop(a);
+    assert!(old.get().is_none());
+
+    // The same allocation now holds a new value, but the
+    // old handle still must not be able to get to it.
+    let b = slot.reuse(value).ok().unwrap();
+    assert_eq!(*b, "second");
+    assert!(old.get().is_none());
+    assert!(slot.get().is_none());
+
+    // Handles from the new generation work as usual.
+    let new = GenArc::downgrade(&b);
+    assert_eq!(*new.get().unwrap(), "second");
+    drop(b);
+    assert!(new.get().is_none());
+}
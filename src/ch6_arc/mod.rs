@@ -1,3 +1,4 @@
 pub mod s1_basic;
 pub mod s2_weak;
 pub mod s3_optimized;
+pub mod gen_handle;
@@ -1,15 +1,25 @@
+use std::alloc::{alloc, handle_alloc_error, Layout};
+use std::borrow::Borrow;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::fence;
 use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
 use std::ptr::NonNull;
 
-struct ArcData<T> {
+// repr(C) so that the header always goes first and the data last. That's
+// the layout Arc<str> builds by hand, see from_str.
+//
+// T can be unsized (str), then ArcData is unsized too and the pointer
+// to it carries the length, same as &str does.
+#[repr(C)]
+struct ArcData<T: ?Sized> {
     ref_count: AtomicUsize,
     data: T,
 }
 
-pub struct Arc<T> {
+pub struct Arc<T: ?Sized> {
     // NotNull not only makes sure statically the pointer is never null
     // but it also alows the compiler to optimize memory placement
     // and use the same size of data for both Arc and Option<Arc>.
@@ -24,9 +34,9 @@ pub struct Arc<T> {
 // to another thread and that thread then can drop it, so T must be Send.
 // - When Arc implements Send it means that the data it stores
 // (the reference) is also sent to another thread, so T must be Sync.
-unsafe impl<T: Send + Sync> Send for Arc<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Send for Arc<T> {}
 // Also since &Arc can be cloned into Arc, the Arc need to implement Sync.
-unsafe impl<T: Send + Sync> Sync for Arc<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for Arc<T> {}
 
 impl<T> Arc<T> {
     pub fn new(data: T) -> Arc<T> {
@@ -40,6 +50,35 @@ impl<T> Arc<T> {
         }
     }
 
+    // Box::new aborts the process if there is no memory. For a huge T it may
+    // be better to get the value back and deal with it, so here we allocate
+    // by hand and check the pointer. Nothing is allocated on failure, and the
+    // value hasn't been moved anywhere yet.
+    pub fn try_new(data: T) -> Result<Arc<T>, T> {
+        Self::try_new_with(data, |layout| unsafe { alloc(layout) })
+    }
+
+    // The allocation is a parameter only so that a test can make it fail.
+    fn try_new_with(data: T, allocate: impl FnOnce(Layout) -> *mut u8) -> Result<Arc<T>, T> {
+        // Never zero sized, there's always the counter.
+        let layout = Layout::new::<ArcData<T>>();
+        let Some(ptr) = NonNull::new(allocate(layout) as *mut ArcData<T>) else {
+            return Err(data);
+        };
+        // Safety: The memory is fresh and has the layout of ArcData<T>.
+        // Drop frees it with Box::from_raw, that's the same allocator
+        // and the same layout that Box::new would have used.
+        unsafe {
+            ptr.as_ptr().write(ArcData {
+                ref_count: AtomicUsize::new(1),
+                data,
+            });
+        }
+        Ok(Arc { ptr })
+    }
+}
+
+impl<T: ?Sized> Arc<T> {
     fn data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
     }
@@ -63,39 +102,218 @@ impl<T> Arc<T> {
             None
         }
     }
-}
 
-impl<T> Deref for Arc<T> {
-    type Target = T;
+    // Whether both point to the same allocation, not whether the values are equal.
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        std::ptr::addr_eq(a.ptr.as_ptr(), b.ptr.as_ptr())
+    }
 
-    fn deref(&self) -> &T {
-        &self.data().data
+    // The address of the data, the same for all the clones. Unlike into_raw
+    // the Arc is only borrowed, so the pointer is good only while it lives.
+    // No counter is touched.
+    pub fn as_ptr(arc: &Self) -> *const T {
+        unsafe { std::ptr::addr_of!((*arc.ptr.as_ptr()).data) }
     }
-}
 
-impl<T> Clone for Arc<T> {
-    fn clone(&self) -> Self {
+    // Same as clone, but too many references is an error instead of an
+    // abort. For a long running server that would rather drop one request
+    // than the whole process. Arc::try_clone(&a) and not a.try_clone(),
+    // same as get_mut, so it doesn't shadow a try_clone of T.
+    pub fn try_clone(arc: &Self) -> Result<Self, CloneError> {
         // Increment the reference count in a thread safe way
-        let new_count = self.data().ref_count.fetch_add(1, Relaxed);
+        let new_count = arc.data().ref_count.fetch_add(1, Relaxed);
 
-        // Fast way to handle possible overflows with an abort.
-        // It is impossible to have usize::MAX / 2 threads running concurrently.
+        // It is impossible to have usize::MAX / 2 threads running concurrently,
+        // so that many references is a leak (mem::forget in a loop) for sure.
         //
         // Relaxed is ok to use here since we don't have operations
         // on other variagbles that got to happen strictly before
         // or after this += operation and the Relaxed ordering
         // would observe the same modification order anyway.
         if new_count > usize::MAX / 2 {
-            std::process::abort();
+            // Undo ours. Other threads can do the same at the same time,
+            // but each takes back only its own, so the count can't wrap:
+            // it would take usize::MAX / 2 of them in between.
+            arc.data().ref_count.fetch_sub(1, Relaxed);
+            return Err(CloneError::TooManyRefs);
         }
 
-        Arc {
-            ptr: self.ptr,
+        #[cfg(feature = "arc-metrics")]
+        if new_count >= CONTENDED_COUNT {
+            CLONE_CONTENTION.fetch_add(1, Relaxed);
         }
+
+        Ok(Arc {
+            ptr: arc.ptr,
+        })
+    }
+
+    // A count that would take forever to get to with real clones.
+    #[cfg(test)]
+    fn set_ref_count(arc: &Self, count: usize) {
+        arc.data().ref_count.store(count, Relaxed);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CloneError {
+    /// The count is over usize::MAX / 2, the references are being leaked.
+    TooManyRefs,
+}
+
+// offset_of needs a sized T, so no raw pointers for Arc<str>.
+impl<T> Arc<T> {
+    // For FFI, the Arc can be handed over as a plain pointer. The pointer
+    // is to the data itself, not to ArcData, so the other side can use it
+    // as a *const T without knowing anything about our reference counting.
+    //
+    // The reference count is not touched. The Arc is "leaked" into the
+    // pointer and it has to come back through from_raw to be dropped.
+    pub fn into_raw(arc: Self) -> *const T {
+        let ptr = unsafe { std::ptr::addr_of!((*arc.ptr.as_ptr()).data) };
+        std::mem::forget(arc);
+        ptr
+    }
+
+    /// # Safety
+    ///
+    /// The pointer must come from `Arc::into_raw` (of the same `T`) and it
+    /// can be turned back into an Arc only once per `into_raw` call.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        // Walk back from the data field to the start of ArcData.
+        let offset = std::mem::offset_of!(ArcData<T>, data);
+        let ptr = ptr.byte_sub(offset) as *mut ArcData<T>;
+        Arc { ptr: NonNull::new_unchecked(ptr) }
     }
 }
 
-impl<T> Drop for Arc<T> {
+impl Arc<str> {
+    // Arc::new can't take a str, it's unsized and can't be passed by value.
+    // So we allocate the header and the bytes in one block ourselves and
+    // copy the bytes over. Same as Arc::new, it's a single allocation, and
+    // the Arc is as cheap to clone as any other.
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Arc<str> {
+        // The same layout the compiler gives ArcData<str> of this length,
+        // so the Box in drop frees exactly what we've allocated here.
+        let (layout, offset) = Layout::new::<AtomicUsize>()
+            .extend(Layout::array::<u8>(s.len()).unwrap())
+            .unwrap();
+        let layout = layout.pad_to_align();
+        unsafe {
+            let mem = alloc(layout);
+            if mem.is_null() {
+                handle_alloc_error(layout);
+            }
+            (mem as *mut AtomicUsize).write(AtomicUsize::new(1));
+            std::ptr::copy_nonoverlapping(s.as_ptr(), mem.add(offset), s.len());
+            // A slice pointer carries the length. Casting keeps the length
+            // and the address, and str has the same metadata as [u8].
+            let ptr = std::ptr::slice_from_raw_parts_mut(mem, s.len()) as *mut ArcData<str>;
+            Arc { ptr: NonNull::new_unchecked(ptr) }
+        }
+    }
+}
+
+impl From<&str> for Arc<str> {
+    fn from(s: &str) -> Self {
+        Arc::from_str(s)
+    }
+}
+
+impl<T: ?Sized> Deref for Arc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data().data
+    }
+}
+
+// Formatting goes through to the value, same as std's Arc does.
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized> AsRef<T> for Arc<T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+// Borrow promises that Eq and Hash of the Arc match the ones of T,
+// so both are by value here. That's what lets a HashMap<Arc<K>, V>
+// be looked up with a plain &K.
+impl<T: ?Sized> Borrow<T> for Arc<T> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized + PartialEq> PartialEq for Arc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for Arc<T> {}
+
+impl<T: ?Sized + Hash> Hash for Arc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T> From<T> for Arc<T> {
+    fn from(data: T) -> Self {
+        Arc::new(data)
+    }
+}
+
+impl<T: Default> Default for Arc<T> {
+    fn default() -> Self {
+        Arc::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Clone for Arc<T> {
+    fn clone(&self) -> Self {
+        // Fast way to handle possible overflows with an abort.
+        // Clone can't fail, and going on would wrap the counter around
+        // and free the data while it's still in use.
+        match Arc::try_clone(self) {
+            Ok(arc) => arc,
+            Err(CloneError::TooManyRefs) => std::process::abort(),
+        }
+    }
+}
+
+// With the arc-metrics feature, counts the clones that found the Arc
+// already shared by CONTENDED_COUNT or more handles. A lot of those means
+// many threads keep bumping the same counter, and its cache line keeps
+// moving between the cores.
+//
+// It's one global counter for all the Arcs. Being one more contended
+// atomic itself, it's only meant for investigations, not for production.
+#[cfg(feature = "arc-metrics")]
+const CONTENDED_COUNT: usize = 8;
+
+#[cfg(feature = "arc-metrics")]
+static CLONE_CONTENTION: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+#[cfg(feature = "arc-metrics")]
+pub fn clone_contention() -> u64 {
+    CLONE_CONTENTION.load(Relaxed)
+}
+
+impl<T: ?Sized> Drop for Arc<T> {
     fn drop(&mut self) {
         // Last decrement that gets the value to 0 means we need to drop the Arc
         // We can't use Relaxed here since we need to ensure that all concurrent
@@ -115,7 +333,9 @@ impl<T> Drop for Arc<T> {
             fence(Acquire);
 
             unsafe {
-                // Box::from_raw reverses Box::leak we did in ctor
+                // Box::from_raw reverses Box::leak we did in ctor. For an
+                // Arc<str> it frees the block from from_str, the layout is
+                // taken from the length in the pointer.
                 drop(Box::from_raw(self.ptr.as_ptr()));
             }
         }
@@ -161,3 +381,174 @@ fn test() {
     // the object should've been dropped.
     assert_eq!(NUM_DROPS.load(Relaxed), 1);
 }
+
+#[test]
+fn test_raw() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop(&'static str);
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let x = Arc::new(DetectDrop("hello"));
+    let y = x.clone();
+
+    let ptr = Arc::into_raw(x);
+    assert_eq!(unsafe { (*ptr).0 }, "hello");
+    assert!(std::ptr::eq(ptr, &*y));
+
+    // The Arc in the raw pointer still keeps the data alive.
+    drop(y);
+    assert_eq!(NUM_DROPS.load(Relaxed), 0);
+
+    let x = unsafe { Arc::from_raw(ptr) };
+    assert_eq!(x.0, "hello");
+    drop(x);
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+}
+
+#[test]
+fn test_format() {
+    let a = Arc::new(42);
+    assert_eq!(format!("{}", a), format!("{}", 42));
+    assert_eq!(format!("{:?}", a), format!("{:?}", 42));
+    // Format flags reach the value too.
+    assert_eq!(format!("{:>5}", Arc::new("x")), "    x");
+    assert_eq!(format!("{:?}", Arc::new(vec![1, 2])), "[1, 2]");
+}
+
+#[test]
+fn test_conversions() {
+    use std::collections::HashMap;
+
+    let a: Arc<i32> = 5.into();
+    assert_eq!(*a, 5);
+    assert_eq!(a.as_ref(), &5);
+
+    let mut map: HashMap<Arc<String>, i32> = HashMap::new();
+    map.insert(Arc::new("one".to_string()), 1);
+    map.insert(String::from("two").into(), 2);
+
+    // Borrow<String> lets us look up by &String. A bare &str would
+    // need Borrow<str> for Arc<String>, which std's Arc doesn't have either.
+    let [one, two, three] = ["one", "two", "three"].map(String::from);
+    assert_eq!(map.get(&one), Some(&1));
+    assert_eq!(map.get(&two), Some(&2));
+    assert_eq!(map.get(&three), None);
+}
+
+#[test]
+fn test_default() {
+    #[derive(Default)]
+    struct Config {
+        values: Arc<Vec<i32>>,
+        name: Arc<String>,
+    }
+
+    let config = Config::default();
+    assert!(config.values.is_empty());
+    assert_eq!(*config.name, "");
+    // Every default is its own allocation.
+    let other = Config::default();
+    assert!(!std::ptr::eq(&*config.values, &*other.values));
+}
+
+#[test]
+fn test_str() {
+    use std::thread;
+
+    let a = Arc::from_str("hello world");
+    let b = a.clone();
+    assert!(Arc::ptr_eq(&a, &b));
+    assert_eq!(&*a, "hello world");
+
+    // Equal contents, but a separate allocation.
+    let c: Arc<str> = "hello world".into();
+    assert!(a == c);
+    assert!(!Arc::ptr_eq(&a, &c));
+
+    thread::scope(|s| {
+        for _ in 0..4 {
+            let b = b.clone();
+            s.spawn(move || {
+                assert_eq!(&*b, "hello world");
+                assert_eq!(b.len(), 11);
+            });
+        }
+    });
+    assert_eq!(a.data().ref_count.load(Relaxed), 2);
+
+    // Lengths that need padding after the bytes, and none at all.
+    assert_eq!(&*Arc::from_str(""), "");
+    assert_eq!(&*Arc::from_str("abc"), "abc");
+}
+
+#[cfg(feature = "arc-metrics")]
+#[test]
+fn test_clone_contention() {
+    use std::thread;
+
+    let arc = Arc::new(0);
+    // Other tests can clone Arcs at the same time, so
+    // we can only count on the counter going up.
+    let before = clone_contention();
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                let clones: Vec<Arc<i32>> = (0..100).map(|_| arc.clone()).collect();
+                drop(clones);
+            });
+        }
+    });
+    let after = clone_contention();
+    assert!(after >= before);
+    assert!(after > 0);
+}
+
+#[test]
+fn test_try_new() {
+    let a = Arc::try_new(vec![1, 2, 3]).unwrap();
+    let b = a.clone();
+    assert_eq!(*b, [1, 2, 3]);
+
+    // Out of memory: the value comes back as it was.
+    let data = vec![String::from("keep"), String::from("me")];
+    let ptr = data.as_ptr();
+    let result = Arc::try_new_with(data, |_| std::ptr::null_mut());
+    let Err(data) = result else { panic!("the allocation didn't fail") };
+    assert_eq!(data, ["keep", "me"]);
+    // Not even copied, the same buffer.
+    assert_eq!(data.as_ptr(), ptr);
+}
+
+#[test]
+fn test_as_ptr() {
+    let a = Arc::new(String::from("x"));
+    let b = a.clone();
+    let c = Arc::new(String::from("x"));
+    assert_eq!(Arc::as_ptr(&a), Arc::as_ptr(&b));
+    assert_ne!(Arc::as_ptr(&a), Arc::as_ptr(&c));
+    assert!(Arc::ptr_eq(&a, &b) && !Arc::ptr_eq(&a, &c));
+    // It points at the data itself.
+    assert!(std::ptr::eq(Arc::as_ptr(&a), &*a));
+}
+
+#[test]
+fn test_try_clone() {
+    let a = Arc::new(1);
+    let b = Arc::try_clone(&a).unwrap();
+    assert_eq!(*b, 1);
+    drop(b);
+
+    Arc::set_ref_count(&a, usize::MAX / 2 + 1);
+    assert!(matches!(Arc::try_clone(&a), Err(CloneError::TooManyRefs)));
+    // The failed attempt didn't leave its increment behind.
+    assert_eq!(a.data().ref_count.load(Relaxed), usize::MAX / 2 + 1);
+
+    // Back to the real count, or the drop would leak the data.
+    Arc::set_ref_count(&a, 1);
+}
@@ -1,4 +1,7 @@
 use std::cell::UnsafeCell;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::ops::Deref;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::fence;
@@ -52,6 +55,43 @@ impl<T> Arc<T> {
         }
     }
 
+    // For self-referential structures, like a parent that owns its children
+    // while the children point back at the parent with a Weak. The Weak is
+    // needed to make the data, but normally a Weak can only be made from an
+    // Arc and the Arc can only be made when the data is already there.
+    //
+    // So we start with an ArcData that has no data and no Arcs, only the
+    // Weak that we pass to the closure. Upgrading it inside of the closure
+    // gives None since data_ref_count is still 0.
+    //
+    // If the closure panics the Weak is dropped while unwinding and
+    // it frees the ArcData, same as the last Weak would normally do.
+    pub fn new_cyclic<F>(data_fn: F) -> Arc<T>
+    where
+        F: FnOnce(&Weak<T>) -> T,
+    {
+        let weak = Weak {
+            ptr: NonNull::from(Box::leak(Box::new(ArcData {
+                alloc_ref_count: AtomicUsize::new(1),
+                data_ref_count: AtomicUsize::new(0),
+                data: UnsafeCell::new(None),
+            }))),
+        };
+
+        let data = data_fn(&weak);
+
+        // Safety: data_ref_count is 0, so no Weak can upgrade
+        // and look at the data while we are writing it.
+        unsafe { *weak.data().data.get() = Some(data) };
+
+        // From now on the Weak`s that the closure made can upgrade.
+        // Release makes sure they would see the data we've just written.
+        weak.data().data_ref_count.store(1, Release);
+
+        // The Weak we've made becomes the one that this Arc owns.
+        Arc { weak }
+    }
+
     pub fn get_mut(arc: &mut Self) -> Option<&mut T> {
         if arc.weak.data().alloc_ref_count.load(Relaxed) == 1 {
             fence(Acquire);
@@ -69,27 +109,80 @@ impl<T> Arc<T> {
         }
     }
 
+    // Clone-on-write. If this Arc is the only pointer to the data we can
+    // mutate it in place. Otherwise the data is cloned into a new allocation
+    // and this Arc is switched over to it, the others keep the old data.
+    //
+    // Same as std, a unique Arc with Weak pointers around clones too. The
+    // Weak`s must never observe a mutation through an upgrade, so they stay
+    // with the old allocation (and can't upgrade anymore once we've left it).
+    pub fn make_mut(arc: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        // get_mut already checks for "one Arc and no Weak`s" via alloc_ref_count.
+        if Arc::get_mut(arc).is_none() {
+            *arc = Arc::new(T::clone(arc));
+        }
+        // Nobody else could have gotten to the fresh allocation, we have &mut to its only Arc.
+        Arc::get_mut(arc).unwrap()
+    }
+
     pub fn downgrade(arc: &Self) -> Weak<T> {
         arc.weak.clone()
     }
+
+    // Identity, as opposed to == that compares the values (see below).
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.weak.ptr == b.weak.ptr
+    }
+
+    // The address of the T inside the Option, the same for all the clones.
+    // Good only while the Arc lives. No counter is touched.
+    pub fn as_ptr(arc: &Self) -> *const T {
+        let data = arc.weak.data().data.get();
+        // Safety: Since there's an Arc, the data is Some, same as in deref.
+        unsafe { (*data).as_ref().unwrap() }
+    }
 }
 
 impl<T> Weak<T> {
+    // A Weak that was never attached to any data, so it never upgrades.
+    // Handy as an initial value for a field that gets a real Weak later.
+    //
+    // There is no ArcData behind it, not even a shared static one (a static
+    // can't be generic over T). The pointer is dangling: non-null and aligned,
+    // but it can't be the address of a real allocation. Every place that would
+    // touch the ArcData through a Weak checks for it first. An Arc never has
+    // such a Weak inside, so the Arc code doesn't need to check.
+    pub fn new() -> Weak<T> {
+        Weak { ptr: NonNull::dangling() }
+    }
+
+    fn is_dangling(&self) -> bool {
+        self.ptr == NonNull::dangling()
+    }
+
     fn data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
     }
 
     pub fn upgrade(&self) -> Option<Arc<T>> {
+        if self.is_dangling() {
+            return None;
+        }
         let mut n = self.data().data_ref_count.load(Relaxed);
         loop {
             if n == 0 {
                 return None;
             }
             assert!(n <= usize::MAX / 2);
+            // Acquire on success syncs with the Release store in new_cyclic,
+            // the data could have been written after this Weak was created.
             if let Err(e) =
                 self.data()
                     .data_ref_count
-                    .compare_exchange_weak(n, n + 1, Relaxed, Relaxed)
+                    .compare_exchange_weak(n, n + 1, Acquire, Relaxed)
             {
                 n = e;
                 continue;
@@ -97,6 +190,35 @@ impl<T> Weak<T> {
             return Some(Arc { weak: self.clone() });
         }
     }
+
+    // How many Arcs are left, 0 once the data is gone. Only a snapshot,
+    // but 0 stays 0: nothing can bring the data back.
+    pub fn strong_count(&self) -> usize {
+        if self.is_dangling() {
+            return 0;
+        }
+        self.data().data_ref_count.load(Relaxed)
+    }
+
+    // Same as upgrade, but makes a single attempt. None means either the
+    // data is gone or another thread changed the counter under us, the
+    // caller decides if it's worth to retry. So the work per call is bounded.
+    //
+    // It's a strong compare_exchange on purpose: a spurious failure of the
+    // weak one would look like a lost race that never happened.
+    pub fn try_upgrade(&self) -> Option<Arc<T>> {
+        if self.is_dangling() {
+            return None;
+        }
+        let counter = &self.data().data_ref_count;
+        let n = counter.load(Relaxed);
+        if n == 0 {
+            return None;
+        }
+        assert!(n <= usize::MAX / 2);
+        counter.compare_exchange(n, n + 1, Acquire, Relaxed).ok()?;
+        Some(Arc { weak: self.clone() })
+    }
 }
 
 impl<T> Deref for Arc<T> {
@@ -110,8 +232,59 @@ impl<T> Deref for Arc<T> {
     }
 }
 
+// Formatting goes through to the value, same as std's Arc does.
+impl<T: fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+// Comparison and hashing are by value, same as std's Arc. Two Arcs to
+// different allocations with equal values are equal, land in the same
+// HashSet bucket and so on. Use Arc::ptr_eq to check for the same allocation.
+impl<T: PartialEq> PartialEq for Arc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl<T: Eq> Eq for Arc<T> {}
+
+impl<T: PartialOrd> PartialOrd for Arc<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (**self).partial_cmp(&**other)
+    }
+}
+
+impl<T: Ord> Ord for Arc<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+impl<T: Hash> Hash for Arc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl<T> Default for Weak<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> Clone for Weak<T> {
     fn clone(&self) -> Self {
+        if self.is_dangling() {
+            return Weak::new();
+        }
         if self.data().alloc_ref_count.fetch_add(1, Relaxed) > usize::MAX / 2 {
             std::process::abort();
         }
@@ -119,6 +292,12 @@ impl<T> Clone for Weak<T> {
     }
 }
 
+impl<T: Default> Default for Arc<T> {
+    fn default() -> Self {
+        Arc::new(T::default())
+    }
+}
+
 impl<T> Clone for Arc<T> {
     fn clone(&self) -> Self {
         let weak = self.weak.clone();
@@ -131,6 +310,9 @@ impl<T> Clone for Arc<T> {
 
 impl<T> Drop for Weak<T> {
     fn drop(&mut self) {
+        if self.is_dangling() {
+            return;
+        }
         if self.data().alloc_ref_count.fetch_sub(1, Release) == 1 {
             fence(Acquire);
             unsafe {
@@ -200,3 +382,152 @@ fn test() {
     assert_eq!(NUM_DROPS.load(Relaxed), 1);
     assert!(z.upgrade().is_none());
 }
+
+#[test]
+fn test_new_cyclic() {
+    struct Parent {
+        name: &'static str,
+        child: Child,
+    }
+
+    struct Child {
+        parent: Weak<Parent>,
+    }
+
+    let parent = Arc::new_cyclic(|weak| {
+        // Nothing to upgrade to yet.
+        assert!(weak.upgrade().is_none());
+        Parent { name: "parent", child: Child { parent: weak.clone() } }
+    });
+
+    let upgraded = parent.child.parent.upgrade().unwrap();
+    assert_eq!(upgraded.name, "parent");
+    assert!(std::ptr::eq(&*upgraded, &*parent));
+
+    // A panic in the closure doesn't leak or double free anything.
+    let result = std::panic::catch_unwind(|| {
+        Arc::<Parent>::new_cyclic(|_| panic!("no parent today"))
+    });
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_make_mut() {
+    // Unique: mutated in place.
+    let mut x = Arc::new(vec![1]);
+    let before = &*x as *const Vec<i32>;
+    Arc::make_mut(&mut x).push(2);
+    assert_eq!(*x, [1, 2]);
+    assert!(std::ptr::eq(before, &*x));
+
+    // Shared with another Arc: the other one keeps the old data.
+    let y = x.clone();
+    Arc::make_mut(&mut x).push(3);
+    assert_eq!(*x, [1, 2, 3]);
+    assert_eq!(*y, [1, 2]);
+    drop(y);
+
+    // Only shared with a Weak: cloned too, so the Weak can't see the change.
+    let weak = Arc::downgrade(&x);
+    Arc::make_mut(&mut x).push(4);
+    assert_eq!(*x, [1, 2, 3, 4]);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_format() {
+    let a = Arc::new(42);
+    assert_eq!(format!("{}", a), format!("{}", 42));
+    assert_eq!(format!("{:?}", a), format!("{:?}", 42));
+    // Format flags reach the value too.
+    assert_eq!(format!("{:>5}", Arc::new("x")), "    x");
+    assert_eq!(format!("{:?}", Arc::new(vec![1, 2])), "[1, 2]");
+}
+
+#[test]
+fn test_compare_and_hash() {
+    use std::collections::{BTreeSet, HashSet};
+
+    let a = Arc::new(2);
+    let b = Arc::new(2);
+    assert!(a == b);
+    assert!(!Arc::ptr_eq(&a, &b));
+    assert!(Arc::ptr_eq(&a, &a.clone()));
+
+    let hashed: HashSet<Arc<i32>> = [a.clone(), b, Arc::new(1)].into_iter().collect();
+    assert_eq!(hashed.len(), 2);
+    assert!(hashed.contains(&Arc::new(1)));
+
+    let sorted: BTreeSet<Arc<i32>> = [Arc::new(3), a, Arc::new(1), Arc::new(3)].into_iter().collect();
+    let values: Vec<i32> = sorted.iter().map(|x| **x).collect();
+    assert_eq!(values, [1, 2, 3]);
+    assert!(Arc::new(1) < Arc::new(2));
+}
+
+#[test]
+fn test_dangling_weak() {
+    let weak = Weak::<String>::new();
+    assert!(weak.upgrade().is_none());
+    let copy = weak.clone();
+    assert!(copy.upgrade().is_none());
+    drop(weak);
+    drop(copy);
+
+    // A zero sized T doesn't make a real Weak look dangling.
+    let arc = Arc::new(());
+    let weak = Arc::downgrade(&arc);
+    assert!(weak.upgrade().is_some());
+    drop(arc);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_try_upgrade() {
+    use std::thread;
+
+    let arc = Arc::new(5);
+    let weak = Arc::downgrade(&arc);
+
+    let successes = AtomicUsize::new(0);
+    let failures = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..8 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    match weak.try_upgrade() {
+                        Some(upgraded) => {
+                            assert_eq!(*upgraded, 5);
+                            successes.fetch_add(1, Relaxed);
+                            thread::yield_now();
+                        }
+                        None => {
+                            failures.fetch_add(1, Relaxed);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    // Lost races are allowed, but every attempt is accounted for
+    // and every success gave its count back.
+    assert_eq!(successes.load(Relaxed) + failures.load(Relaxed), 8000);
+    assert!(successes.load(Relaxed) > 0);
+    assert_eq!(weak.data().data_ref_count.load(Relaxed), 1);
+
+    drop(arc);
+    assert!(weak.try_upgrade().is_none());
+    assert!(Weak::<i32>::new().try_upgrade().is_none());
+}
+
+#[test]
+fn test_as_ptr() {
+    let a = Arc::new(String::from("x"));
+    let b = a.clone();
+    let c = Arc::new(String::from("x"));
+    assert_eq!(Arc::as_ptr(&a), Arc::as_ptr(&b));
+    assert_ne!(Arc::as_ptr(&a), Arc::as_ptr(&c));
+    assert!(Arc::ptr_eq(&a, &b) && !Arc::ptr_eq(&a, &c));
+    // It points at the data itself.
+    assert!(std::ptr::eq(Arc::as_ptr(&a), &*a));
+}
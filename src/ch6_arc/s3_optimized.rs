@@ -1,7 +1,8 @@
 use std::cell::UnsafeCell;
+use std::fmt;
 use std::mem::ManuallyDrop;
 use std::ops::Deref;
-use std::ptr::NonNull;
+use std::ptr::{addr_of, addr_of_mut, NonNull};
 use std::sync::atomic::fence;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
@@ -73,6 +74,46 @@ impl<T> Arc<T> {
         unsafe { self.ptr.as_ref() }
     }
 
+    // Same as new_cyclic in s2_weak, but here we can't start with a None
+    // since t_data is not an Option. The allocation is made uninitialized
+    // and t_data is written only after the closure returns.
+    //
+    // The Weak handed to the closure is counted in alloc_ref_count. It
+    // becomes the implicit weak pointer that represents all the Arcs.
+    //
+    // If the closure panics, the last Weak frees the ArcData with t_data
+    // never written. That's fine since ManuallyDrop never drops it.
+    pub fn new_cyclic<F>(data_fn: F) -> Arc<T>
+    where
+        F: FnOnce(&Weak<T>) -> T,
+    {
+        let mut uninit = Box::<ArcData<T>>::new_uninit();
+        let ptr = uninit.as_mut_ptr();
+        // Safety: Writing the fields of our own fresh allocation.
+        unsafe {
+            addr_of_mut!((*ptr).data_ref_count).write(AtomicUsize::new(0));
+            addr_of_mut!((*ptr).alloc_ref_count).write(AtomicUsize::new(1));
+        }
+        let weak = Weak {
+            ptr: NonNull::from(Box::leak(uninit)).cast::<ArcData<T>>(),
+        };
+
+        let data = data_fn(&weak);
+
+        // Safety: data_ref_count is 0, so no Weak can upgrade and look at
+        // t_data while we are writing it. `write` doesn't read or drop
+        // the uninitialized value that is there now.
+        unsafe { weak.arc_data().t_data.get().write(ManuallyDrop::new(data)) };
+
+        // Release makes sure an upgrade would see the data we've just written.
+        weak.arc_data().data_ref_count.store(1, Release);
+
+        let arc = Arc { ptr: weak.ptr };
+        // Not dropping it, we are handing the count over to the Arc.
+        std::mem::forget(weak);
+        arc
+    }
+
     // Here is the kind of code we would like to be able to handle:
     //
     // let mut arc = Arc::new(42);
@@ -150,6 +191,104 @@ impl<T> Arc<T> {
         unsafe { Some(&mut *arc.arc_data().t_data.get()) }
     }
 
+    // make_mut of s2_weak for this layout. Unique means get_mut says so:
+    // one Arc and no Weak`s, checked under the usize::MAX lock, so a
+    // downgrade in the middle of it waits and can't slip in. Anything
+    // else and the data is cloned into a new allocation that only this
+    // Arc points to. The other Arcs keep the old data, and the Weak`s stay
+    // with the old allocation too, they never see a mutation through an
+    // upgrade.
+    pub fn get_mut_or_clone(arc: &mut Self) -> &mut T
+    where
+        T: Clone,
+    {
+        if Arc::get_mut(arc).is_none() {
+            *arc = Arc::new(T::clone(arc));
+        }
+        // Nobody else could have gotten to the fresh allocation, we have &mut to its only Arc.
+        Arc::get_mut(arc).unwrap()
+    }
+
+    // Gives back the T if this is the last Arc, otherwise returns the Arc
+    // back to the caller untouched, so no reference is lost either way.
+    pub fn try_unwrap(arc: Self) -> Result<T, Self> {
+        // Going from 1 straight to 0 means we are the last Arc. It's the same
+        // as what the final Arc::drop does, except that T is moved out
+        // instead of being dropped. Weak pointers would see 0 from now on
+        // and would not be able to upgrade.
+        //
+        // No need to look at alloc_ref_count like get_mut does. Outstanding
+        // Weak`s are fine here, they just keep the ArcData alive without T.
+        if arc
+            .arc_data()
+            .data_ref_count
+            .compare_exchange(1, 0, Relaxed, Relaxed)
+            .is_err()
+        {
+            return Err(arc);
+        }
+        // Same as in Arc::drop - all the other Arc drops happened before this.
+        fence(Acquire);
+
+        // We've already done the data_ref_count decrement of Arc::drop.
+        let arc = ManuallyDrop::new(arc);
+
+        // Safety: The data reference counter is zero, so nothing else will
+        // access the data, and t_data is not going to be dropped a second
+        // time since ManuallyDrop doesn't drop its content.
+        let data = unsafe { ManuallyDrop::take(&mut *arc.arc_data().t_data.get()) };
+
+        // Drop the implicit weak pointer that represented all `Arc<T>`s.
+        drop(Weak { ptr: arc.ptr });
+        Ok(data)
+    }
+
+    // Both counts are a snapshot. Other threads can clone or drop their
+    // pointers at any moment, so the value could be stale right away.
+    // Good for debugging (e.g. looking for reference cycles), but nothing
+    // should be synchronized on them.
+    pub fn strong_count(arc: &Self) -> usize {
+        arc.arc_data().data_ref_count.load(Acquire)
+    }
+
+    pub fn weak_count(arc: &Self) -> usize {
+        let mut n = arc.arc_data().alloc_ref_count.load(Acquire);
+        // usize::MAX means get_mut is in the middle of its check.
+        // It's a short one, so we just wait it out like downgrade does.
+        while n == usize::MAX {
+            std::hint::spin_loop();
+            n = arc.arc_data().alloc_ref_count.load(Acquire);
+        }
+        // Minus the implicit weak pointer that represents all the Arcs.
+        n - 1
+    }
+
+    // Identity, as opposed to comparing the values.
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        a.ptr == b.ptr
+    }
+
+    // The address of the data, the same for all the clones. ManuallyDrop
+    // has the layout of T, so the cell's pointer is a pointer to T. Good
+    // only while the Arc lives. No counter is touched.
+    pub fn as_ptr(arc: &Self) -> *const T {
+        arc.arc_data().t_data.get() as *const T
+    }
+
+    // Both counters as they are, without the corrections strong_count and
+    // weak_count make. For the tests that check the counter optimization.
+    #[cfg(test)]
+    pub(crate) fn debug_counts(arc: &Self) -> (usize, usize) {
+        let data = arc.arc_data();
+        let mut alloc = data.alloc_ref_count.load(Relaxed);
+        // Same as weak_count, get_mut is in the middle of its check.
+        while alloc == usize::MAX {
+            std::hint::spin_loop();
+            alloc = data.alloc_ref_count.load(Relaxed);
+        }
+        (data.data_ref_count.load(Relaxed), alloc)
+    }
+
     pub fn downgrade(arc: &Self) -> Weak<T> {
         let mut n = arc.arc_data().alloc_ref_count.load(Relaxed);
         loop {
@@ -173,6 +312,51 @@ impl<T> Arc<T> {
             return Weak { ptr: arc.ptr };
         }
     }
+
+    // Same as in s1_basic, the pointer goes to the data itself. Both
+    // UnsafeCell and ManuallyDrop have the layout of what they wrap,
+    // so a pointer to t_data is a pointer to the T.
+    pub fn into_raw(arc: Self) -> *const T {
+        let ptr = unsafe { UnsafeCell::raw_get(addr_of!((*arc.ptr.as_ptr()).t_data)) };
+        std::mem::forget(arc);
+        ptr as *const T
+    }
+
+    /// # Safety
+    ///
+    /// The pointer must come from `Arc::into_raw` (of the same `T`) and it
+    /// can be turned back into an Arc only once per `into_raw` call.
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        let offset = std::mem::offset_of!(ArcData<T>, t_data);
+        let ptr = ptr.byte_sub(offset) as *mut ArcData<T>;
+        Arc { ptr: NonNull::new_unchecked(ptr) }
+    }
+
+    // For the other side of FFI that only holds the raw pointer. It can
+    // take or give back a strong reference without ever having an Arc.
+    //
+    // Both go through a temporary Arc, so the counting is exactly what
+    // clone and drop do, the last decrement included.
+
+    /// # Safety
+    ///
+    /// The pointer must come from `Arc::into_raw` and the strong count
+    /// must be at least 1 for the whole call.
+    pub unsafe fn increment_strong_count(ptr: *const T) {
+        // ManuallyDrop so that the borrowed count is not given back.
+        let arc = ManuallyDrop::new(Arc::from_raw(ptr));
+        // This count is now owned by whoever holds the pointer.
+        std::mem::forget(Arc::clone(&arc));
+    }
+
+    /// # Safety
+    ///
+    /// The pointer must come from `Arc::into_raw` and the strong count
+    /// must be at least 1. The decrement gives up one of the references
+    /// that the pointer holder owns, the data can be dropped right here.
+    pub unsafe fn decrement_strong_count(ptr: *const T) {
+        drop(Arc::from_raw(ptr));
+    }
 }
 
 impl<T> Deref for Arc<T> {
@@ -185,6 +369,19 @@ impl<T> Deref for Arc<T> {
     }
 }
 
+// Formatting goes through to the value, same as std's Arc does.
+impl<T: fmt::Debug> fmt::Debug for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Arc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
 impl<T> Weak<T> {
     fn arc_data(&self) -> &ArcData<T> {
         unsafe { self.ptr.as_ref() }
@@ -199,10 +396,12 @@ impl<T> Weak<T> {
                 return None;
             }
             assert!(n <= usize::MAX / 2);
+            // Acquire on success syncs with the Release store in new_cyclic,
+            // the data could have been written after this Weak was created.
             if let Err(e) =
                 self.arc_data()
                     .data_ref_count
-                    .compare_exchange_weak(n, n + 1, Relaxed, Relaxed)
+                    .compare_exchange_weak(n, n + 1, Acquire, Relaxed)
             {
                 n = e;
                 continue;
@@ -210,6 +409,18 @@ impl<T> Weak<T> {
             return Some(Arc { ptr: self.ptr });
         }
     }
+
+    // upgrade, or a brand new Arc from f if the data is gone. For caches
+    // that rebuild a value on demand.
+    //
+    // The new Arc has its own allocation, it's not the one this Weak points
+    // to. The old one can't be reused: the data is dropped already, and
+    // other Weaks may still point at it, they'd suddenly upgrade to a value
+    // they never saw. So this Weak stays dead, and the caller should keep
+    // a downgrade of the returned Arc if it wants to find the new one again.
+    pub fn upgrade_or_else<F: FnOnce() -> T>(&self, f: F) -> Arc<T> {
+        self.upgrade().unwrap_or_else(|| Arc::new(f()))
+    }
 }
 
 impl<T> Clone for Weak<T> {
@@ -239,6 +450,12 @@ impl<T> Drop for Weak<T> {
     }
 }
 
+impl<T: Default> Default for Arc<T> {
+    fn default() -> Self {
+        Arc::new(T::default())
+    }
+}
+
 impl<T> Clone for Arc<T> {
     fn clone(&self) -> Self {
         // All this optimization is needed to skip the following commented line
@@ -318,3 +535,218 @@ fn test() {
     assert_eq!(NUM_DROPS.load(Relaxed), 1);
     assert!(z.upgrade().is_none());
 }
+
+#[test]
+fn test_try_unwrap() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop;
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let x = Arc::new(DetectDrop);
+    let y = x.clone();
+    let weak = Arc::downgrade(&x);
+
+    // Not unique, the same Arc is returned.
+    let x = match Arc::try_unwrap(x) {
+        Ok(_) => panic!("unwrapped a shared Arc"),
+        Err(x) => x,
+    };
+    drop(y);
+
+    let data = match Arc::try_unwrap(x) {
+        Ok(data) => data,
+        Err(_) => panic!("failed to unwrap a unique Arc"),
+    };
+    assert_eq!(NUM_DROPS.load(Relaxed), 0);
+    assert!(weak.upgrade().is_none());
+
+    drop(data);
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+
+    // The weak pointer keeps the ArcData, but not the data.
+    drop(weak);
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+}
+
+#[test]
+fn test_counts() {
+    let x = Arc::new(1);
+    let y = x.clone();
+    let a = Arc::downgrade(&x);
+    let b = Arc::downgrade(&y);
+    let c = b.clone();
+    assert_eq!(Arc::strong_count(&x), 2);
+    assert_eq!(Arc::weak_count(&x), 3);
+
+    drop((y, a));
+    assert_eq!(Arc::strong_count(&x), 1);
+    assert_eq!(Arc::weak_count(&x), 2);
+
+    drop((b, c));
+    assert_eq!(Arc::weak_count(&x), 0);
+}
+
+#[test]
+fn test_new_cyclic() {
+    struct Parent {
+        name: &'static str,
+        child: Child,
+    }
+
+    struct Child {
+        parent: Weak<Parent>,
+    }
+
+    let parent = Arc::new_cyclic(|weak| {
+        // Nothing to upgrade to yet.
+        assert!(weak.upgrade().is_none());
+        Parent { name: "parent", child: Child { parent: weak.clone() } }
+    });
+
+    let upgraded = parent.child.parent.upgrade().unwrap();
+    assert_eq!(upgraded.name, "parent");
+    assert!(std::ptr::eq(&*upgraded, &*parent));
+    drop(upgraded);
+    assert_eq!(Arc::weak_count(&parent), 1);
+
+    // A panic in the closure doesn't leak or double free anything.
+    let result = std::panic::catch_unwind(|| {
+        Arc::<Parent>::new_cyclic(|_| panic!("no parent today"))
+    });
+    assert!(result.is_err());
+}
+
+#[test]
+fn test_format() {
+    let a = Arc::new(42);
+    assert_eq!(format!("{}", a), format!("{}", 42));
+    assert_eq!(format!("{:?}", a), format!("{:?}", 42));
+    // Format flags reach the value too.
+    assert_eq!(format!("{:>5}", Arc::new("x")), "    x");
+    assert_eq!(format!("{:?}", Arc::new(vec![1, 2])), "[1, 2]");
+}
+
+#[test]
+fn test_raw_strong_count() {
+    static NUM_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    struct DetectDrop(&'static str);
+
+    impl Drop for DetectDrop {
+        fn drop(&mut self) {
+            NUM_DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    let x = Arc::new(DetectDrop("hello"));
+    let weak = Arc::downgrade(&x);
+    let ptr = Arc::into_raw(x);
+    assert_eq!(unsafe { (*ptr).0 }, "hello");
+
+    // The pointer holder takes one more reference for itself.
+    unsafe { Arc::increment_strong_count(ptr) };
+    let owner = unsafe { Arc::from_raw(ptr) };
+    assert_eq!(Arc::strong_count(&owner), 2);
+
+    // The owning Arc is gone, the raw reference keeps the data alive.
+    drop(owner);
+    assert_eq!(NUM_DROPS.load(Relaxed), 0);
+    assert_eq!(unsafe { (*ptr).0 }, "hello");
+    assert!(weak.upgrade().is_some());
+
+    unsafe { Arc::decrement_strong_count(ptr) };
+    assert_eq!(NUM_DROPS.load(Relaxed), 1);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_counter_optimization() {
+    let x = Arc::new(1);
+    // One Arc, and the implicit Weak that stands for all the Arcs.
+    assert_eq!(Arc::debug_counts(&x), (1, 1));
+
+    // Cloning an Arc doesn't touch the allocation counter.
+    let y = x.clone();
+    let z = y.clone();
+    assert_eq!(Arc::debug_counts(&x), (3, 1));
+
+    // A real Weak does.
+    let weak = Arc::downgrade(&x);
+    assert_eq!(Arc::debug_counts(&x), (3, 2));
+    drop((y, z));
+    assert_eq!(Arc::debug_counts(&x), (1, 2));
+
+    // Only the last Arc gives the implicit Weak up, the real one keeps
+    // the allocation. Upgrading doesn't touch the allocation counter either.
+    let upgraded = weak.upgrade().unwrap();
+    assert_eq!(Arc::debug_counts(&upgraded), (2, 2));
+    drop(upgraded);
+    drop(x);
+    assert_eq!(weak.arc_data().alloc_ref_count.load(Relaxed), 1);
+    assert_eq!(weak.arc_data().data_ref_count.load(Relaxed), 0);
+}
+
+#[test]
+fn test_as_ptr() {
+    let a = Arc::new(String::from("x"));
+    let b = a.clone();
+    let c = Arc::new(String::from("x"));
+    assert_eq!(Arc::as_ptr(&a), Arc::as_ptr(&b));
+    assert_ne!(Arc::as_ptr(&a), Arc::as_ptr(&c));
+    assert!(Arc::ptr_eq(&a, &b) && !Arc::ptr_eq(&a, &c));
+    // It points at the data itself.
+    assert!(std::ptr::eq(Arc::as_ptr(&a), &*a));
+}
+
+#[test]
+fn test_upgrade_or_else() {
+    let arc = Arc::new(1);
+    let weak = Arc::downgrade(&arc);
+
+    // Alive, so f isn't called and it's the same allocation.
+    let same = weak.upgrade_or_else(|| unreachable!());
+    assert!(Arc::ptr_eq(&arc, &same));
+    drop((arc, same));
+
+    let mut called = false;
+    let fresh = weak.upgrade_or_else(|| {
+        called = true;
+        2
+    });
+    assert!(called);
+    assert_eq!(*fresh, 2);
+    // The fresh one is on its own, the old Weak still can't upgrade.
+    assert!(weak.upgrade().is_none());
+    assert_eq!(Arc::strong_count(&fresh), 1);
+}
+
+#[test]
+fn test_get_mut_or_clone() {
+    // Unique: mutated in place.
+    let mut x = Arc::new(vec![1]);
+    let before = Arc::as_ptr(&x);
+    Arc::get_mut_or_clone(&mut x).push(2);
+    assert_eq!(*x, [1, 2]);
+    assert_eq!(before, Arc::as_ptr(&x));
+
+    // Shared with another Arc: the other one keeps the old data.
+    let y = x.clone();
+    Arc::get_mut_or_clone(&mut x).push(3);
+    assert_eq!(*x, [1, 2, 3]);
+    assert_eq!(*y, [1, 2]);
+    assert_eq!(Arc::strong_count(&y), 1);
+    drop(y);
+
+    // Only shared with a Weak: cloned too, so the Weak can't see the change.
+    let weak = Arc::downgrade(&x);
+    Arc::get_mut_or_clone(&mut x).push(4);
+    assert_eq!(*x, [1, 2, 3, 4]);
+    assert!(weak.upgrade().is_none());
+    assert_eq!(Arc::debug_counts(&x), (1, 1));
+}
@@ -0,0 +1,87 @@
+use crate::futex::{wait, wake_all};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+
+// Same idea as the latch, but the threads that arrive also wait for the
+// others, and it can be used over and over again for every next phase.
+//
+// We can't wait on `waiting` itself: it's reset to 0 for the next phase,
+// and a thread that is fast enough could already bump it again before a
+// slow one wakes up and looks at it. Then the slow one would see a value
+// that looks like the phase is still going and would wait forever. The
+// generation only ever goes up, so a change of it is never missed.
+pub struct Barrier {
+    /// How many threads have to arrive for the barrier to open.
+    count: u32,
+    /// How many threads have arrived in the current phase.
+    waiting: AtomicU32,
+    /// Bumped every time the barrier opens. That's what we wait on.
+    generation: AtomicU32,
+}
+
+impl Barrier {
+    pub const fn new(n: u32) -> Self {
+        assert!(n > 0, "a barrier needs at least one thread");
+        Self {
+            count: n,
+            waiting: AtomicU32::new(0),
+            generation: AtomicU32::new(0),
+        }
+    }
+
+    // Returns true for exactly one thread of each phase, the last one to
+    // arrive. The leader can do the work that needs to happen only once
+    // between the phases.
+    pub fn wait(&self) -> bool {
+        // Taken before we say we are here. If we did that after, the leader
+        // could open the barrier in between, and we'd wait for the next one.
+        let generation = self.generation.load(Acquire);
+
+        // AcqRel makes all the increments (and the loads before them) of this
+        // phase visible to the leader, and the leader's work to everyone
+        // through the generation.
+        if self.waiting.fetch_add(1, AcqRel) + 1 == self.count {
+            // Nobody else touches waiting until the generation changes.
+            self.waiting.store(0, Relaxed);
+            self.generation.fetch_add(1, Release);
+            wake_all(&self.generation);
+            return true;
+        }
+
+        while self.generation.load(Acquire) == generation {
+            wait(&self.generation, generation);
+        }
+        false
+    }
+}
+
+#[test]
+fn two_phases() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    let barrier = Barrier::new(3);
+    let phase_one = AtomicUsize::new(0);
+    let phase_two = AtomicUsize::new(0);
+    let leaders = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                phase_one.fetch_add(1, Relaxed);
+                if barrier.wait() {
+                    leaders.fetch_add(1, Relaxed);
+                }
+                // Everybody is done with the first phase before anyone
+                // gets to the second one.
+                assert_eq!(phase_one.load(Relaxed), 3);
+
+                phase_two.fetch_add(1, Relaxed);
+                if barrier.wait() {
+                    leaders.fetch_add(1, Relaxed);
+                }
+                assert_eq!(phase_two.load(Relaxed), 3);
+            });
+        }
+    });
+    assert_eq!(leaders.load(Relaxed), 2);
+}
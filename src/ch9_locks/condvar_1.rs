@@ -1,7 +1,10 @@
-use atomic_wait::{wait, wake_all, wake_one};
+use crate::futex::{wait, wake_all, wake_one};
 use std::sync::atomic::AtomicU32;
+#[cfg(debug_assertions)]
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
 use std::sync::atomic::Ordering::Relaxed;
 use super::mutex_3::MutexGuard;
+use std::sync::LockResult;
 
 pub struct Condvar {
     // We don't really count anything, we just need something that
@@ -23,11 +26,27 @@ pub struct Condvar {
     // future other platforms would use this mechanism as well and
     // for the caller it would be just another spurious wakeup.
     notify_events: AtomicU32,
+
+    // A condvar works only with one mutex at a time. Waiters that unlock
+    // different mutexes race with notifiers in a way the counter above
+    // doesn't cover, and wakeups get lost. So in debug builds we remember
+    // the mutex of the current waiters and complain about a different one.
+    // Once nobody waits the condvar is free to be used with another mutex.
+    #[cfg(debug_assertions)]
+    mutex: AtomicPtr<()>,
+    #[cfg(debug_assertions)]
+    waiters: AtomicUsize,
 }
 
 impl Condvar {
     pub const fn new() -> Self {
-        Self { notify_events: AtomicU32::new(0) }
+        Self {
+            notify_events: AtomicU32::new(0),
+            #[cfg(debug_assertions)]
+            mutex: AtomicPtr::new(std::ptr::null_mut()),
+            #[cfg(debug_assertions)]
+            waiters: AtomicUsize::new(0),
+        }
     }
 
     pub fn notify_one(&self) {
@@ -44,8 +63,10 @@ impl Condvar {
     // Note that it is not the mutex that is being moved here but just the guard.
     // There is one mutex, there could be several guards.
     //
-    // The method returns a mutex that is locked.
-    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    // The method returns a mutex that is locked. Same as with std, if the
+    // mutex got poisoned while we were waiting the guard comes back in
+    // the error, and the caller decides if the data is still usable.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
         // The memory ordering explanation is not clear to me. From what I understand
         // it it based on the fact that mutex lock and unlock/drop are already ordered
         // operations (mutex uses acquire/release under the hood that causes all the
@@ -66,6 +87,9 @@ impl Condvar {
         // need to be long sress tests.
         let counter_value = self.notify_events.load(Relaxed);
 
+        #[cfg(debug_assertions)]
+        let mutex_ptr = self.enter_wait(guard.mutex as *const _ as *mut ());
+
         // Unlock the mutex by dropping the guard, but remember the
         // original mutex so we can lock it again later.
         //
@@ -82,8 +106,54 @@ impl Condvar {
         // Wait, but only if the counter hasn't changed since unlocking.
         wait(&self.notify_events, counter_value);
 
+        #[cfg(debug_assertions)]
+        self.leave_wait(mutex_ptr);
+
         mutex.lock()
     }
+
+    // The check is best effort - the last waiter leaving and a new one
+    // coming with another mutex at the very same moment could be reported
+    // as a misuse. That's acceptable for a debug-only check.
+    #[cfg(debug_assertions)]
+    fn enter_wait(&self, mutex: *mut ()) -> *mut () {
+        self.waiters.fetch_add(1, Relaxed);
+        match self.mutex.compare_exchange(std::ptr::null_mut(), mutex, Relaxed, Relaxed) {
+            Ok(_) => {}
+            Err(current) if current == mutex => {}
+            Err(_) => {
+                self.waiters.fetch_sub(1, Relaxed);
+                panic!("Condvar is used with two different mutexes at the same time");
+            }
+        }
+        mutex
+    }
+
+    #[cfg(debug_assertions)]
+    fn leave_wait(&self, mutex: *mut ()) {
+        if self.waiters.fetch_sub(1, Relaxed) == 1 {
+            // Could have been taken over already, so only reset our own.
+            let _ = self.mutex.compare_exchange(mutex, std::ptr::null_mut(), Relaxed, Relaxed);
+        }
+    }
+
+    // The spurious wakeup loop that every caller of wait has to write anyway.
+    // Keeps waiting while the condition holds, same as std's Condvar::wait_while.
+    // Stops at the first poisoned wakeup, the condition isn't checked on
+    // data that could be left half updated by the panic.
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> LockResult<MutexGuard<'a, T>>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard)?;
+        }
+        Ok(guard)
+    }
 }
 
 #[test]
@@ -112,7 +182,7 @@ fn test_condvar() {
             // would coordinate with a scheduler to put this thread
             // to sleep until the main thread would explicitly
             // drop the mutex guard.
-            *mutex.lock() = 123;
+            *mutex.lock().unwrap() = 123;
 
             // Notify via condition variable that it is time
             // for another thread to check if the data it
@@ -129,7 +199,7 @@ fn test_condvar() {
             condvar.notify_one();
         });
 
-        let mut m = mutex.lock();
+        let mut m = mutex.lock().unwrap();
 
         // There needs to be a loop to handle possible spurious wakeups.
         while *m < 100 {
@@ -147,7 +217,7 @@ fn test_condvar() {
             //
             // Also the wait operation would unlock the mutex to let
             // another thread to do something with the data in it.
-            m = condvar.wait(m);
+            m = condvar.wait(m).unwrap();
 
             // Wakeup would be at least 1. It could be more
             // since it is possible that we will be woken up
@@ -166,3 +236,97 @@ fn test_condvar() {
     // a thread sleep system call in this test.
     assert!(wakeups < 10);
 }
+
+#[test]
+fn test_wait_while() {
+    use super::mutex_3::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    let mutex = Mutex::new(0);
+    let condvar = Condvar::new();
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(100));
+            *mutex.lock().unwrap() = 123;
+            condvar.notify_one();
+        });
+
+        let m = condvar.wait_while(mutex.lock().unwrap(), |count| *count < 100).unwrap();
+        assert_eq!(*m, 123);
+    });
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn test_two_mutexes() {
+    use super::mutex_3::Mutex;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::thread;
+    use std::time::Duration;
+
+    let m1 = Mutex::new(false);
+    let m2 = Mutex::new(false);
+    let condvar = Condvar::new();
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let mut m = m1.lock().unwrap();
+            while !*m {
+                m = condvar.wait(m).unwrap();
+            }
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let misuse = catch_unwind(AssertUnwindSafe(|| condvar.wait(m2.lock().unwrap())));
+        assert!(misuse.is_err());
+
+        *m1.lock().unwrap() = true;
+        condvar.notify_all();
+    });
+
+    // The panic above happened while m2 was locked.
+    assert!(m2.is_poisoned());
+
+    // Nobody waits anymore, so another mutex is fine now.
+    let m3 = Mutex::new(false);
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            *m3.lock().unwrap() = true;
+            condvar.notify_all();
+        });
+        drop(condvar.wait_while(m3.lock().unwrap(), |ready| !*ready).unwrap());
+    });
+}
+
+#[test]
+fn test_wait_poisoned() {
+    use super::mutex_3::Mutex;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::thread;
+
+    let mutex = Mutex::new(false);
+    let condvar = Condvar::new();
+
+    // Locked before the notifier starts, so it can only get in once we wait.
+    let guard = mutex.lock().unwrap();
+    thread::scope(|s| {
+        s.spawn(|| {
+            let panicked = catch_unwind(AssertUnwindSafe(|| {
+                let mut m = mutex.lock().unwrap();
+                *m = true;
+                condvar.notify_one();
+                panic!("notifier panicked under the lock");
+            }));
+            assert!(panicked.is_err());
+        });
+
+        match condvar.wait_while(guard, |ready| !*ready) {
+            Ok(_) => panic!("the waiter should have seen the poison"),
+            // The data is still there for whoever wants to look.
+            Err(poisoned) => assert!(*poisoned.into_inner()),
+        }
+    });
+}
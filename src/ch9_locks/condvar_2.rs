@@ -1,8 +1,10 @@
-use atomic_wait::{wait, wake_all, wake_one};
+use crate::futex::{wait, wait_timeout, wake_all, wake_one};
 use std::sync::atomic::AtomicU32;
 use std::sync::atomic::AtomicUsize;
-use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::Ordering::{Acquire, Relaxed};
+use std::time::{Duration, Instant};
 use super::mutex_3::MutexGuard;
+use std::sync::{LockResult, PoisonError};
 
 pub struct Condvar {
     counter: AtomicU32,
@@ -21,7 +23,10 @@ impl Condvar {
         }
     }
 
-    pub fn notify_one(&self) {
+    // Returns whether a wake call was made at all. That is only a hint:
+    // a waiter seen here may be gone (or about to wake anyway) by the
+    // time the wake reaches it.
+    pub fn notify_one(&self) -> bool {
         // My explanation why the Relaxed ordering is enough here is:
         // since all the cores would observe the modifications in the
         // same order and we structure the code in a way that the num_waiters
@@ -65,11 +70,31 @@ impl Condvar {
             // is allowed to consume notifications. But this solution requires more
             // data to be traked (resources) and is complex to implement.
             wake_one(&self.counter);
+            true
+        } else {
+            false
         }
     }
 
-    pub fn notify_all(&self) {
-        if self.num_waiters.load(Relaxed) > 0 {
+    // notify_one, but only if the predicate says the waiter has a reason
+    // to wake up. The producer usually knows what the consumers wait for,
+    // e.g. a whole batch rather than one more item, and so can skip waking
+    // one up only to see it go back to sleep.
+    //
+    // Meant to be called with the waiters' mutex held, so the predicate can
+    // look at the same data as their condition does. That's fine: we never
+    // lock the caller's mutex here.
+    pub fn notify_if<F: FnOnce() -> bool>(&self, predicate: F) -> bool {
+        predicate() && self.notify_one()
+    }
+
+    // Returns the number of waiters we saw right before the wake call.
+    // That count is racy - waiters come and go at any moment, some could
+    // be leaving on a timeout or a spurious wakeup. Good enough for metrics,
+    // not for making decisions.
+    pub fn notify_all(&self) -> usize {
+        let waiters = self.num_waiters.load(Relaxed);
+        if waiters > 0 {
             self.counter.fetch_add(1, Relaxed);
             // Another optimization opportunity here is to handle the so called the
             // thundering herd problem. Wake all wakes many threads here and it's quite
@@ -83,9 +108,22 @@ impl Condvar {
             // This can be handled in OSes that can do requeue operation.
             wake_all(&self.counter);
         }
+        waiters
+    }
+
+    // Same as notify_all, but tells whether there was anyone to notify.
+    //
+    // `true` means there was at least one waiter at the moment we checked
+    // num_waiters. It could have been woken up by something else (or
+    // spuriously) right after that. `false` means nobody was waiting at
+    // that moment and no wake call was made at all.
+    pub fn notify_all_checked(&self) -> bool {
+        self.notify_all() > 0
     }
 
-    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+    // A panic under the mutex poisons whoever waits on it too, same as
+    // with std: the guard comes back in the error, not dropped silently.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
         self.num_waiters.fetch_add(1, Relaxed);
         let counter_value = self.counter.load(Relaxed);
         let mutex = guard.mutex;
@@ -108,6 +146,96 @@ impl Condvar {
         // waiting thread that can be woken up in the case num_waiters is 0.
         mutex.lock()
     }
+
+    // Same as wait, but gives up after the timeout. The bool is true when
+    // that happened. Spurious wakeups are possible here too, so the caller
+    // should recheck the condition and the time left, same as with wait.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> LockResult<(MutexGuard<'a, T>, bool)> {
+        self.num_waiters.fetch_add(1, Relaxed);
+        let counter_value = self.counter.load(Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+
+        let woken = wait_timeout(&self.counter, counter_value, timeout);
+
+        // On the timeout path too. Otherwise notify would keep waking
+        // a waiter that is long gone, or worse, num_waiters would never
+        // drop back to 0.
+        self.num_waiters.fetch_sub(1, Relaxed);
+        match mutex.lock() {
+            Ok(guard) => Ok((guard, !woken)),
+            Err(poisoned) => Err(PoisonError::new((poisoned.into_inner(), !woken))),
+        }
+    }
+
+    // For waiting on a progress counter, e.g. until the workers have done
+    // `target` items. Sleeps on the counter itself instead of ours: nobody
+    // has to notify, and the check is a load, not a lock of the mutex and
+    // a look at the data on every wakeup.
+    //
+    // The counter must only ever go up (and not wrap around), otherwise a
+    // wait could miss its target. And whoever bumps it has to wake us up
+    // with futex::wake_all on the counter. Between two bumps we sleep,
+    // each bump below the target wakes us once for nothing.
+    //
+    // The mutex isn't held while we wait, same as with wait, and the
+    // guard comes back locked. We don't count as a waiter here, nobody
+    // notifies us through the condvar.
+    pub fn wait_for_counter<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        counter: &AtomicU32,
+        target: u32,
+    ) -> LockResult<MutexGuard<'a, T>> {
+        let mutex = guard.mutex;
+        drop(guard);
+        loop {
+            // Acquire, so that the work that was counted is visible to us.
+            let current = counter.load(Acquire);
+            if current >= target {
+                break;
+            }
+            // Returns right away if it was bumped since the load.
+            wait(counter, current);
+        }
+        mutex.lock()
+    }
+
+    // wait_timeout in a loop while the condition holds. The timeout is for
+    // the whole call, not for each wait: every spurious wakeup or a notify
+    // that found the condition still true only gets what's left of it.
+    //
+    // The bool is true if we gave up with the condition still holding.
+    // A condition that turned false right at the deadline is not a timeout.
+    // num_waiters is taken care of by wait_timeout on every round.
+    //
+    // A poisoned wakeup ends the loop right away, with the bool of that
+    // round. The condition isn't checked on data the panic left behind.
+    pub fn wait_timeout_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        timeout: Duration,
+        mut condition: F,
+    ) -> LockResult<(MutexGuard<'a, T>, bool)>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if !condition(&mut *guard) {
+                return Ok((guard, false));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok((guard, true));
+            }
+            guard = self.wait_timeout(guard, deadline - now)?.0;
+        }
+    }
 }
 
 #[test]
@@ -124,13 +252,13 @@ fn test_condvar() {
     thread::scope(|s| {
         s.spawn(|| {
             thread::sleep(Duration::from_secs(1));
-            *mutex.lock() = 123;
+            *mutex.lock().unwrap() = 123;
             condvar.notify_one();
         });
 
-        let mut m = mutex.lock();
+        let mut m = mutex.lock().unwrap();
         while *m < 100 {
-            m = condvar.wait(m);
+            m = condvar.wait(m).unwrap();
             wakeups += 1;
         }
 
@@ -141,3 +269,291 @@ fn test_condvar() {
     // while still allowing for a few spurious wake ups.
     assert!(wakeups < 10);
 }
+
+#[test]
+fn test_notify_all_checked() {
+    use super::mutex_3::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    let mutex = Mutex::new(false);
+    let condvar = Condvar::new();
+
+    // Nobody is waiting yet.
+    assert!(!condvar.notify_all_checked());
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let mut m = mutex.lock().unwrap();
+            while !*m {
+                m = condvar.wait(m).unwrap();
+            }
+        });
+
+        // Give the thread time to block in wait.
+        thread::sleep(Duration::from_millis(100));
+        *mutex.lock().unwrap() = true;
+        assert!(condvar.notify_all_checked());
+    });
+
+    assert!(!condvar.notify_all_checked());
+}
+
+#[test]
+fn test_wait_timeout() {
+    use super::mutex_3::Mutex;
+    use std::thread;
+    use std::time::Instant;
+
+    let mutex = Mutex::new(false);
+    let condvar = Condvar::new();
+
+    // Nobody notifies.
+    let start = Instant::now();
+    let (guard, timed_out) = condvar.wait_timeout(mutex.lock().unwrap(), Duration::from_millis(50)).unwrap();
+    assert!(timed_out);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert!(!*guard);
+    drop(guard);
+    assert_eq!(condvar.num_waiters.load(Relaxed), 0);
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(50));
+            *mutex.lock().unwrap() = true;
+            condvar.notify_one();
+        });
+        let mut m = mutex.lock().unwrap();
+        while !*m {
+            let timed_out;
+            (m, timed_out) = condvar.wait_timeout(m, Duration::from_secs(10)).unwrap();
+            assert!(!timed_out);
+        }
+    });
+}
+
+#[test]
+fn test_notify_all_count() {
+    use super::mutex_3::Mutex;
+    use std::thread;
+
+    let mutex = Mutex::new(false);
+    let condvar = Condvar::new();
+
+    assert!(!condvar.notify_one());
+    assert_eq!(condvar.notify_all(), 0);
+
+    thread::scope(|s| {
+        for _ in 0..3 {
+            s.spawn(|| {
+                let mut m = mutex.lock().unwrap();
+                while !*m {
+                    m = condvar.wait(m).unwrap();
+                }
+            });
+        }
+
+        // Wait for at least one waiter to block. The scope end checks that
+        // all three get through.
+        while condvar.num_waiters.load(Relaxed) == 0 {
+            thread::yield_now();
+        }
+        *mutex.lock().unwrap() = true;
+        assert!(condvar.notify_all() >= 1);
+    });
+}
+
+#[test]
+fn test_wait_timeout_while() {
+    use super::mutex_3::Mutex;
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+    let condvar = Condvar::new();
+
+    // The condition never becomes false, even with notifications coming in.
+    thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..5 {
+                thread::sleep(Duration::from_millis(10));
+                *mutex.lock().unwrap() += 1;
+                condvar.notify_all();
+            }
+        });
+        let start = Instant::now();
+        let (guard, timed_out) =
+            condvar.wait_timeout_while(mutex.lock().unwrap(), Duration::from_millis(100), |_| true).unwrap();
+        assert!(timed_out);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        // Still locked by us.
+        assert!(mutex.is_locked());
+        drop(guard);
+    });
+    assert_eq!(condvar.num_waiters.load(Relaxed), 0);
+
+    // And one that does, well before the timeout.
+    thread::scope(|s| {
+        s.spawn(|| {
+            thread::sleep(Duration::from_millis(10));
+            *mutex.lock().unwrap() = 100;
+            condvar.notify_one();
+        });
+        let (guard, timed_out) =
+            condvar.wait_timeout_while(mutex.lock().unwrap(), Duration::from_secs(10), |x| *x < 100).unwrap();
+        assert!(!timed_out);
+        assert_eq!(*guard, 100);
+    });
+}
+
+#[test]
+fn test_wait_poisoned() {
+    use super::mutex_3::Mutex;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::thread;
+
+    let mutex = Mutex::new(false);
+    let condvar = Condvar::new();
+
+    // Locked before the notifier starts, so it can only get in once we wait.
+    let mut m = mutex.lock().unwrap();
+    thread::scope(|s| {
+        s.spawn(|| {
+            // The notifier panics with the lock held. The notify happens
+            // before the unwind gets to the guard, so the waiter wakes up
+            // and then finds the mutex poisoned.
+            let panicked = catch_unwind(AssertUnwindSafe(|| {
+                let mut m = mutex.lock().unwrap();
+                *m = true;
+                condvar.notify_one();
+                panic!("notifier panicked under the lock");
+            }));
+            assert!(panicked.is_err());
+        });
+
+        while !*m {
+            match condvar.wait(m) {
+                Ok(guard) => m = guard,
+                Err(poisoned) => {
+                    // The data is still there for whoever wants to look.
+                    assert!(*poisoned.into_inner());
+                    return;
+                }
+            }
+        }
+        panic!("the waiter should have seen the poison");
+    });
+}
+
+#[test]
+fn test_waiter_panics() {
+    use super::mutex_3::Mutex;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::thread;
+
+    // (ready, number of waiters)
+    let mutex = Mutex::new((false, 0));
+    let condvar = Condvar::new();
+
+    let waiter = || {
+        let mut m = mutex.lock().unwrap();
+        m.1 += 1;
+        loop {
+            match condvar.wait(m) {
+                // The first one to get the lock back panics with it.
+                Ok(guard) if guard.0 => panic!("waiter panicked under the lock"),
+                Ok(guard) => m = guard,
+                // The second one finds the mutex poisoned, and can
+                // still get to the data.
+                Err(poisoned) => return poisoned.into_inner().0,
+            }
+        }
+    };
+
+    let results = thread::scope(|s| {
+        let waiters = [
+            s.spawn(|| catch_unwind(AssertUnwindSafe(waiter))),
+            s.spawn(|| catch_unwind(AssertUnwindSafe(waiter))),
+        ];
+        // Both count themselves under the lock and unlock only in wait.
+        loop {
+            let mut m = mutex.lock().unwrap();
+            if m.1 == 2 {
+                m.0 = true;
+                condvar.notify_all();
+                break;
+            }
+            drop(m);
+            thread::yield_now();
+        }
+        waiters.map(|w| w.join().unwrap())
+    });
+
+    assert_eq!(results.iter().filter(|r| r.is_err()).count(), 1);
+    assert!(results.iter().any(|r| matches!(r, Ok(true))));
+    assert!(mutex.is_poisoned());
+}
+
+#[test]
+fn test_notify_if() {
+    use super::mutex_3::Mutex;
+    use std::thread;
+
+    let mutex = Mutex::new(0);
+    let condvar = Condvar::new();
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            let mut m = mutex.lock().unwrap();
+            while *m < 10 {
+                m = condvar.wait(m).unwrap();
+            }
+        });
+        while condvar.num_waiters.load(Relaxed) == 0 {
+            thread::yield_now();
+        }
+
+        // Not enough yet, the waiter stays asleep.
+        let mut m = mutex.lock().unwrap();
+        *m = 5;
+        assert!(!condvar.notify_if(|| *m >= 10));
+        drop(m);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(condvar.num_waiters.load(Relaxed), 1);
+
+        let mut m = mutex.lock().unwrap();
+        *m = 10;
+        assert!(condvar.notify_if(|| *m >= 10));
+    });
+    assert_eq!(condvar.num_waiters.load(Relaxed), 0);
+}
+
+#[test]
+fn test_wait_for_counter() {
+    use super::mutex_3::Mutex;
+    use std::sync::atomic::Ordering::Release;
+    use std::thread;
+
+    let mutex = Mutex::new(None);
+    let condvar = Condvar::new();
+    let counter = AtomicU32::new(0);
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            for step in 1..=5 {
+                thread::sleep(Duration::from_millis(10));
+                counter.fetch_add(1, Release);
+                wake_all(&counter);
+                // Don't go on before the waiter has looked, so that what
+                // it sees is the step that woke it up.
+                while step == 3 && mutex.lock().unwrap().is_none() {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut seen = condvar.wait_for_counter(mutex.lock().unwrap(), &counter, 3).unwrap();
+        *seen = Some(counter.load(Relaxed));
+    });
+    assert_eq!(*mutex.lock().unwrap(), Some(3));
+    assert_eq!(counter.into_inner(), 5);
+}
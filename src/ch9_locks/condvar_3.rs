@@ -0,0 +1,176 @@
+use crate::futex::{wait, wait_timeout, wake_one};
+use std::collections::VecDeque;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::{Arc, LockResult, PoisonError};
+use std::time::{Duration, Instant};
+use super::mutex_3::{Mutex, MutexGuard};
+
+pub struct Condvar {
+    // condvar_2 (the book's version) has a single counter that all the
+    // waiters wait on, plus the number of waiters to skip the wake calls
+    // when there is nobody to wake. notify_one there wakes up whichever
+    // waiter the OS picks, and a thread could wait for a long time while
+    // the ones that came after it are served.
+    //
+    // Now every waiter has its own futex word and stands in line here,
+    // notify_one takes the one that waits the longest. It's fair, and a
+    // wake goes to exactly the thread that is notified, so the others
+    // don't wake up for nothing.
+    //
+    // Why not just tickets, as the writers in rwlock_3 have: a waiter that
+    // times out would have to give its ticket back from the middle of the
+    // line, and that's not possible with two counters. From a queue it can
+    // just remove itself.
+    //
+    // The queue is behind our own mutex. It's held only for a push or a pop,
+    // but it costs an allocation per wait, and notify is not free anymore
+    // when nobody waits. That's the price of the fairness.
+    //
+    // Only the waiting and the notifying are different from condvar_2. The
+    // helpers on top of them (wait_timeout_while, notify_if, ...) would be
+    // the same code, so they are not repeated here.
+    waiters: Mutex<VecDeque<Arc<AtomicU32>>>,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // Nothing panics while the queue is locked,
+    // so it can't be poisoned.
+    fn waiters(&self) -> MutexGuard<'_, VecDeque<Arc<AtomicU32>>> {
+        self.waiters.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Returns whether there was a waiter to wake up.
+    pub fn notify_one(&self) -> bool {
+        let waiter = self.waiters().pop_front();
+        match waiter {
+            Some(waiter) => {
+                notify(&waiter);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns the number of waiters woken up. Unlike the counter based
+    // version, this is exact: each of them was waiting and got its own wake.
+    pub fn notify_all(&self) -> usize {
+        let waiters = std::mem::take(&mut *self.waiters());
+        for waiter in &waiters {
+            notify(waiter);
+        }
+        waiters.len()
+    }
+
+    // A panic under the mutex poisons whoever waits on it too, same as
+    // with std: the guard comes back in the error, not dropped silently.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
+        let waiter = self.enqueue();
+        let mutex = guard.mutex;
+
+        // We are in the line before the mutex is unlocked. Whoever changes
+        // the condition does that under the mutex, so their notify comes
+        // after that and finds us there. No notification is lost.
+        drop(guard);
+
+        // Our word changes only when we are notified, no spurious wakeups
+        // from other waiters being woken.
+        while waiter.load(Acquire) == 0 {
+            wait(&waiter, 0);
+        }
+
+        mutex.lock()
+    }
+
+    // Same as wait, but gives up after the timeout. The bool is true when
+    // that happened. The caller should still recheck the condition, same
+    // as with wait: another thread could have got to the data first.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        timeout: Duration,
+    ) -> LockResult<(MutexGuard<'a, T>, bool)> {
+        let waiter = self.enqueue();
+        let mutex = guard.mutex;
+        drop(guard);
+
+        let deadline = Instant::now() + timeout;
+        let mut timed_out = false;
+        while waiter.load(Acquire) == 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                // If we are still in the line, leave it. Otherwise a notify
+                // would be spent on us later, and a waiter behind us would
+                // miss it. If we are not in the line, a notify has already
+                // taken us out, and we count as notified.
+                let mut waiters = self.waiters();
+                if let Some(i) = waiters.iter().position(|w| Arc::ptr_eq(w, &waiter)) {
+                    waiters.remove(i);
+                    timed_out = true;
+                }
+                break;
+            }
+            wait_timeout(&waiter, 0, deadline - now);
+        }
+        match mutex.lock() {
+            Ok(guard) => Ok((guard, timed_out)),
+            Err(poisoned) => Err(PoisonError::new((poisoned.into_inner(), timed_out))),
+        }
+    }
+
+    fn enqueue(&self) -> Arc<AtomicU32> {
+        let waiter = Arc::new(AtomicU32::new(0));
+        self.waiters().push_back(waiter.clone());
+        waiter
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The waiter is already out of the line, nobody else would notify it.
+// The Arc keeps the word alive even if the waiter is gone by now.
+fn notify(waiter: &AtomicU32) {
+    waiter.store(1, Release);
+    wake_one(waiter);
+}
+
+#[test]
+fn test_notify_one_fifo() {
+    use std::thread;
+
+    let mutex = Mutex::new(Vec::new());
+    let condvar = Condvar::new();
+
+    thread::scope(|s| {
+        for id in 0..3 {
+            let (mutex, condvar) = (&mutex, &condvar);
+            s.spawn(move || {
+                let mut woken = condvar.wait(mutex.lock().unwrap()).unwrap();
+                woken.push(id);
+            });
+            // The next one starts only when this one is in the line.
+            while condvar.waiters().len() <= id {
+                thread::yield_now();
+            }
+        }
+
+        for n in 1..=3 {
+            assert!(condvar.notify_one());
+            while mutex.lock().unwrap().len() < n {
+                thread::yield_now();
+            }
+        }
+        assert!(!condvar.notify_one());
+    });
+    assert_eq!(*mutex.lock().unwrap(), [0, 1, 2]);
+}
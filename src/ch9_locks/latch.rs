@@ -0,0 +1,77 @@
+use crate::futex::{wait, wake_all};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// Lets threads wait until some number of events happened, e.g. until all
+// the workers finished their startup phase. Unlike a Barrier, the ones
+// that count down don't wait and the ones that wait don't count.
+//
+// It's single use. Once the count is zero every wait returns right away.
+pub struct CountDownLatch {
+    /// How many count_down calls are still missing.
+    count: AtomicU32,
+}
+
+impl CountDownLatch {
+    pub const fn new(n: u32) -> Self {
+        Self {
+            count: AtomicU32::new(n),
+        }
+    }
+
+    // Extra calls after the count got to zero do nothing. Going below zero
+    // would wrap around the u32 and the waiters would block again.
+    pub fn count_down(&self) {
+        // Release so that whatever we did before counting down is visible
+        // to the threads that get through wait.
+        let result = self
+            .count
+            .fetch_update(Release, Relaxed, |count| count.checked_sub(1));
+        if result == Ok(1) {
+            wake_all(&self.count);
+        }
+    }
+
+    pub fn wait(&self) {
+        loop {
+            let count = self.count.load(Acquire);
+            if count == 0 {
+                return;
+            }
+            // Returns right away if the count has changed since the load,
+            // so a count_down in between doesn't get lost.
+            wait(&self.count, count);
+        }
+    }
+
+    // A snapshot, can be stale by the time the caller looks at it.
+    pub fn count(&self) -> u32 {
+        self.count.load(Relaxed)
+    }
+}
+
+#[test]
+fn all_workers_count_down() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    const N: usize = 8;
+    let latch = CountDownLatch::new(N as u32);
+    let started = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..N {
+            s.spawn(|| {
+                thread::yield_now();
+                started.fetch_add(1, Relaxed);
+                latch.count_down();
+            });
+        }
+        latch.wait();
+        assert_eq!(started.load(Relaxed), N);
+    });
+
+    // Counting down past zero saturates.
+    latch.count_down();
+    assert_eq!(latch.count(), 0);
+    latch.wait();
+}
@@ -3,6 +3,12 @@ pub mod mutex_2;
 pub mod mutex_3;
 pub mod condvar_1;
 pub mod condvar_2;
+pub mod condvar_3;
 pub mod rwlock_1;
 pub mod rwlock_2;
 pub mod rwlock_3;
+pub mod sharded_map;
+pub mod reentrant_mutex;
+pub mod latch;
+pub mod barrier;
+pub mod semaphore;
@@ -1,14 +1,26 @@
-use atomic_wait::{wait, wake_one};
-use std::cell::UnsafeCell;
+use crate::backoff::Backoff;
+use crate::futex::{wait, wait_timeout, wake_one};
+use std::cell::{Cell, UnsafeCell};
+use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::AtomicU32;
+use std::sync::atomic::{AtomicBool, AtomicU32};
+use std::sync::{Arc, LockResult, PoisonError, TryLockError, TryLockResult};
 use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::time::{Duration, Instant};
 
 pub struct Mutex<T> {
     /// 0: unlocked
     /// 1: locked, no other threads waiting
     /// 2: locked, other threads waiting
     state: AtomicU32,
+    /// Set when a thread panicked while holding the lock. The data could
+    /// be left half-updated then, so lock reports that to everyone after.
+    /// It's only ever touched under the lock, so Relaxed is enough.
+    poisoned: AtomicBool,
+    /// How long to spin before going to sleep, see lock_contended.
+    /// It's only read on the contended path, so the fast path doesn't
+    /// pay for it except for the extra 4 bytes.
+    spin_limit: u32,
     value: UnsafeCell<T>,
 }
 
@@ -28,9 +40,14 @@ pub struct MutexGuard<'a, T> {
     // that would violate the corectness guarantees that
     // the thread syncronization library (create) provides.
     pub(crate) mutex: &'a Mutex<T>,
+    // Whether the thread was already panicking when it took the lock. Then
+    // the lock is used by some cleanup code during unwinding, and that is
+    // not a reason to poison the mutex. Same as std does it.
+    panicking: bool,
 }
 
 unsafe impl<T> Sync for MutexGuard<'_, T> where T: Sync {}
+unsafe impl<T> Sync for ArcMutexGuard<T> where T: Sync {}
 
 impl<T> Deref for MutexGuard<'_, T> {
     type Target = T;
@@ -47,33 +64,190 @@ impl<T> DerefMut for MutexGuard<'_, T> {
 
 impl<T> Mutex<T> {
     pub const fn new(value: T) -> Self {
+        Self::with_spin_limit(value, DEFAULT_SPIN_LIMIT)
+    }
+
+    // 0 means going straight to the syscall when the lock is taken.
+    pub const fn with_spin_limit(value: T, spins: u32) -> Self {
         Self {
             state: AtomicU32::new(0), // unlocked state
+            poisoned: AtomicBool::new(false),
+            spin_limit: spins,
             value: UnsafeCell::new(value),
         }
     }
 
+    // No locking needed when we own the mutex or borrow it exclusively,
+    // no other thread could be holding it then. The state isn't touched.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
     // One can add #[inline] suggestion to the compiler for such a small method
     // it can result in a slighly larger but faster codebase. For small methods
     // usually the fast benefit is there and the code size is almost the same.
     //
     // However it is not clear why the compiler can't figure it out by itself.
-    pub fn lock(&self) -> MutexGuard<T> {
+    //
+    // Same as std, the lock is taken even if the mutex is poisoned. The error
+    // just makes the caller decide: unwrap to pass the panic along, or take
+    // the guard out of the error with into_inner and deal with the data.
+    pub fn lock(&self) -> LockResult<MutexGuard<T>> {
         if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
             // Since the lock was already locked we need to wait
             // But probably it would be unlocked soon, so we'll be smart about it
-            lock_contended(&self.state);
+            lock_contended(&self.state, self.spin_limit);
+        }
+        self.locked()
+    }
+
+    // The guard borrows nothing, it keeps the mutex alive by owning an Arc
+    // to it. So it can be moved to another thread or stored in a struct.
+    // Unlocking from another thread is fine, the futex doesn't care who
+    // wakes the waiters.
+    //
+    // Poisoning is reported the same way as by lock, only the guard in
+    // the error is an ArcMutexGuard.
+    pub fn lock_arc(self: &Arc<Self>) -> LockResult<ArcMutexGuard<T>> {
+        let guard = self.lock().unwrap_or_else(PoisonError::into_inner);
+        let panicking = guard.panicking;
+        // The unlock is done by the ArcMutexGuard from now on.
+        std::mem::forget(guard);
+        let guard = ArcMutexGuard {
+            mutex: Arc::clone(self),
+            panicking,
+            _not_sync: PhantomData,
+        };
+        // We still hold the lock, nobody can poison it in between.
+        if self.poisoned.load(Relaxed) {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    // Only to be called when the lock is taken. Every way to lock ends here,
+    // so they all report poisoning the same way.
+    fn locked(&self) -> LockResult<MutexGuard<'_, T>> {
+        // A plain load on the fast path, nothing is allocated.
+        let guard = self.guard();
+        if self.poisoned.load(Relaxed) {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    // Only to be called when the lock is taken.
+    fn guard(&self) -> MutexGuard<'_, T> {
+        MutexGuard {
+            mutex: self,
+            panicking: std::thread::panicking(),
+        }
+    }
+
+    // For diagnostics and assertions only. The answer can be out of date
+    // by the time the caller looks at it, another thread can lock or unlock
+    // right after the load.
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Relaxed) != 0
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Relaxed)
+    }
+
+    // The lock is held exactly for the duration of the closure. The guard
+    // never reaches the caller, so it can't be kept around by mistake. If the
+    // closure panics the guard's drop still unlocks (and poisons) the mutex.
+    //
+    // Panics if the mutex is already poisoned, same as lock().unwrap().
+    pub fn with_lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        let mut guard = self.lock().unwrap();
+        f(&mut guard)
+    }
+
+    // Takes the lock only if it is free right now. No spinning and no syscall.
+    //
+    // We go from 0 to 1 same as the fast path of lock. Some other thread may
+    // promote the state to 2 while we hold the lock, but the guard's drop
+    // handles that the same way it does for a guard from lock.
+    //
+    // Same as std: WouldBlock if the lock is taken, Poisoned with the guard
+    // in it if we got the lock but the mutex is poisoned.
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_ok() {
+            Ok(self.locked()?)
+        } else {
+            Err(TryLockError::WouldBlock)
+        }
+    }
+
+    // Between try_lock and lock: spins for a while if the lock is taken,
+    // but never sleeps in a syscall. For real-time threads that must not
+    // block. WouldBlock if the lock is still taken after max_spins checks,
+    // poisoning is reported as with try_lock.
+    //
+    // Loads while spinning, same as lock_contended. We only go for the
+    // compare_exchange when the lock looks free, so waiting here doesn't
+    // keep invalidating the cache line for the holder. 0 spins is a try_lock.
+    pub fn lock_spin(&self, max_spins: u32) -> TryLockResult<MutexGuard<'_, T>> {
+        let mut spins = 0;
+        loop {
+            if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_ok() {
+                return Ok(self.locked()?);
+            }
+            loop {
+                if spins == max_spins {
+                    return Err(TryLockError::WouldBlock);
+                }
+                spins += 1;
+                std::hint::spin_loop();
+                if self.state.load(Relaxed) == 0 {
+                    break;
+                }
+            }
         }
-        MutexGuard { mutex: self }
+    }
+
+    // Gives up with WouldBlock if the lock can't be taken before the
+    // timeout. Poisoning is reported as with try_lock.
+    //
+    // The waiting loop is the same as in lock_contended, the state only ever
+    // goes to 2 while the lock is held by someone else. So a waiter that
+    // times out leaves the lock either locked (the holder would reset it to
+    // 0 on unlock, maybe with one unnecessary wake) or untouched.
+    pub fn lock_timeout(&self, timeout: Duration) -> TryLockResult<MutexGuard<'_, T>> {
+        if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_ok() {
+            return Ok(self.locked()?);
+        }
+        // A wait can end early - spurious wakeup or a wake that was meant
+        // for someone else. So we wait against a deadline and each time
+        // wait only for the time that is left.
+        let deadline = Instant::now() + timeout;
+        while self.state.swap(2, Acquire) != 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(TryLockError::WouldBlock);
+            }
+            wait_timeout(&self.state, 2, deadline - now);
+        }
+        Ok(self.locked()?)
     }
 }
 
+pub(crate) const DEFAULT_SPIN_LIMIT: u32 = 100;
+
 // One can add the #[cold] hint for compiller. It would suggest that this is
 // not a common code path and we expect that most of the time lock can be
 // done with the `if compare_exchange(0, 1)`.
 //
 // #[cold]
-fn lock_contended(state: &AtomicU32) {
+pub(crate) fn lock_contended(state: &AtomicU32, spin_limit: u32) {
     let mut spin_count = 0;
 
     // Load is used first since compare and exchange is costlier.
@@ -94,12 +268,17 @@ fn lock_contended(state: &AtomicU32) {
     // If the state is already 2 then another thread already tried
     // spinning and it didn't help. So we go straight to the syscall.
     //
-    // 100 cycles is a random number. There is no single best value here,
-    // it all depends on the platform and the OS. 100 is a reasonably
-    // good practical value that was used in Rust 1.6 on Linux.
-    while state.load(Relaxed) == 1 && spin_count < 100 {
-        spin_count += 1;
-        std::hint::spin_loop();
+    // 100 cycles (the default) is a random number. There is no single best
+    // value here, it all depends on the platform and the OS. 100 is a
+    // reasonably good practical value that was used in Rust 1.6 on Linux.
+    // That's why the limit can be changed per mutex with with_spin_limit.
+    //
+    // The limit counts spin_loop hints, but we don't check the state after
+    // every one of them. Backoff makes the gaps between the loads grow
+    // 1, 2, 4, ... hints, so a long critical section costs fewer loads.
+    let backoff = Backoff::new();
+    while state.load(Relaxed) == 1 && spin_count < spin_limit {
+        spin_count += backoff.spin();
     }
 
     // Try to acquire the lock without making a system call.
@@ -115,6 +294,112 @@ fn lock_contended(state: &AtomicU32) {
 
 impl<T> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
+        // Being dropped during unwinding means the thread
+        // panicked somewhere in the middle of using the data.
+        if !self.panicking && std::thread::panicking() {
+            self.mutex.poisoned.store(true, Relaxed);
+        }
+        if self.mutex.state.swap(0, Release) == 2 {
+            wake_one(&self.mutex.state);
+        }
+    }
+}
+
+// A guard that still holds the whole mutex locked but gives access only
+// to a part of the protected value, e.g. to one field of a struct.
+//
+// It can't keep the &Mutex<T> since T is not in its type anymore. But
+// all that is needed to unlock is the state, and the projected value is
+// kept as a pointer. PhantomData tells the compiler that we act as if we
+// had a &'a mut U, so the borrow can't outlive the lock.
+pub struct MappedMutexGuard<'a, U> {
+    state: &'a AtomicU32,
+    poisoned: &'a AtomicBool,
+    panicking: bool,
+    value: *mut U,
+    _marker: PhantomData<&'a mut U>,
+}
+
+unsafe impl<U> Sync for MappedMutexGuard<'_, U> where U: Sync {}
+
+impl<'a, T> MutexGuard<'a, T> {
+    // An associated function rather than a method, so it doesn't shadow
+    // a `map` method of T that is reachable through Deref.
+    pub fn map<U, F>(mut guard: Self, f: F) -> MappedMutexGuard<'a, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        // If f panics the guard is still around and unlocks the mutex.
+        let value: *mut U = f(&mut guard);
+        let state = &guard.mutex.state;
+        let poisoned = &guard.mutex.poisoned;
+        let panicking = guard.panicking;
+        // From now on unlocking is the job of the mapped guard.
+        std::mem::forget(guard);
+        MappedMutexGuard {
+            state,
+            poisoned,
+            panicking,
+            value,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<U> Deref for MappedMutexGuard<'_, U> {
+    type Target = U;
+    fn deref(&self) -> &U {
+        unsafe { &*self.value }
+    }
+}
+
+impl<U> DerefMut for MappedMutexGuard<'_, U> {
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { &mut *self.value }
+    }
+}
+
+impl<U> Drop for MappedMutexGuard<'_, U> {
+    fn drop(&mut self) {
+        if !self.panicking && std::thread::panicking() {
+            self.poisoned.store(true, Relaxed);
+        }
+        if self.state.swap(0, Release) == 2 {
+            wake_one(self.state);
+        }
+    }
+}
+
+pub struct ArcMutexGuard<T> {
+    mutex: Arc<Mutex<T>>,
+    panicking: bool,
+    // Sharing the guard shares the T, so the guard can be Sync only when T
+    // is (see the impl at the top). Without this field the compiler would
+    // derive Sync from the Arc<Mutex<T>>, which only needs T: Send. Cell is
+    // Send but not Sync, so the guard can still be moved to another thread.
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T> Deref for ArcMutexGuard<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for ArcMutexGuard<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+// Same as MutexGuard's. The Arc itself is dropped after this, so the
+// mutex can't go away before it's unlocked.
+impl<T> Drop for ArcMutexGuard<T> {
+    fn drop(&mut self) {
+        if !self.panicking && std::thread::panicking() {
+            self.mutex.poisoned.store(true, Relaxed);
+        }
         if self.mutex.state.swap(0, Release) == 2 {
             wake_one(&self.mutex.state);
         }
@@ -139,10 +424,10 @@ fn main() {
         // Lock should be very fast to lock and unlock
         // There are no other threads, so the state
         // would just shift between 0 and 1
-        *m.lock() += 1;
+        *m.lock().unwrap() += 1;
     }
     let duration = start.elapsed();
-    println!("locked {} times in {:?}", *m.lock(), duration);
+    println!("locked {} times in {:?}", *m.lock().unwrap(), duration);
 }
 
 // TODO (bench)
@@ -160,14 +445,285 @@ fn main2() {
             // But it is good to test anyway
             s.spawn(|| {
                 for _ in 0..5_000_000 {
-                    *m.lock() += 1;
+                    *m.lock().unwrap() += 1;
                 }
             });
         }
     });
     let duration = start.elapsed();
-    println!("locked {} times in {:?}", *m.lock(), duration);
+    println!("locked {} times in {:?}", *m.lock().unwrap(), duration);
 
-    let value = *m.lock();
+    let value = *m.lock().unwrap();
     assert!(value == 5_000_000 * 4, "value = {}", value);
 }
+
+#[test]
+fn test_try_lock() {
+    use std::sync::Barrier;
+    use std::thread;
+
+    let m = Mutex::new(0);
+    let locked = Barrier::new(2);
+    let checked = Barrier::new(2);
+    thread::scope(|s| {
+        s.spawn(|| {
+            let mut guard = m.lock().unwrap();
+            locked.wait();
+            checked.wait();
+            *guard += 1;
+        });
+        locked.wait();
+        assert!(matches!(m.try_lock(), Err(TryLockError::WouldBlock)));
+        checked.wait();
+        // The other thread releases the lock soon, lock waits for that.
+        drop(m.lock().unwrap());
+    });
+    let mut guard = m.try_lock().unwrap();
+    *guard += 1;
+    assert_eq!(*guard, 2);
+}
+
+#[test]
+fn test_lock_timeout() {
+    use std::thread;
+
+    let m = Mutex::new(0);
+    let (locked, wait_locked) = std::sync::mpsc::channel();
+    thread::scope(|s| {
+        s.spawn(|| {
+            let _guard = m.lock().unwrap();
+            locked.send(()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        });
+        wait_locked.recv().unwrap();
+        let start = Instant::now();
+        assert!(matches!(m.lock_timeout(Duration::from_millis(50)), Err(TryLockError::WouldBlock)));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(elapsed < Duration::from_millis(200));
+
+        // Long enough to outlast the holder.
+        *m.lock_timeout(Duration::from_secs(10)).unwrap() += 1;
+    });
+    // The timed out waiter didn't leave the lock stuck.
+    assert_eq!(*m.try_lock().unwrap(), 1);
+}
+
+#[test]
+fn test_into_inner() {
+    let mut m = Mutex::new(vec![1]);
+    m.lock().unwrap().push(2);
+    m.get_mut().push(3);
+    assert_eq!(m.into_inner(), [1, 2, 3]);
+}
+
+#[test]
+fn test_map() {
+    struct Big {
+        name: &'static str,
+        hits: u32,
+    }
+
+    let m = Mutex::new(Big { name: "big", hits: 0 });
+    std::thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    let mut hits = MutexGuard::map(m.lock().unwrap(), |big| &mut big.hits);
+                    *hits += 1;
+                }
+            });
+        }
+    });
+    let big = m.into_inner();
+    assert_eq!(big.name, "big");
+    assert_eq!(big.hits, 4000);
+}
+
+#[test]
+fn test_poison() {
+    use std::thread;
+
+    let m = Mutex::new(0);
+    thread::scope(|s| {
+        let panicked = s.spawn(|| {
+            let mut guard = m.lock().unwrap();
+            *guard = 1;
+            panic!("half way through");
+        });
+        assert!(panicked.join().is_err());
+    });
+
+    assert!(m.is_poisoned());
+    // Still usable for those who know how to fix the data.
+    let Err(poisoned) = m.lock() else { panic!("should be poisoned") };
+    let guard = poisoned.into_inner();
+    assert_eq!(*guard, 1);
+    drop(guard);
+    assert!(m.lock().is_err());
+}
+
+#[test]
+fn test_poison_every_lock() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let m = Arc::new(Mutex::new(0));
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        let _guard = m.lock().unwrap();
+        panic!("half way through");
+    }));
+    assert!(result.is_err());
+    assert!(m.is_poisoned());
+
+    // Each of them still takes the lock, and gives the guard back in the error.
+    let Err(TryLockError::Poisoned(p)) = m.try_lock() else { panic!("try_lock") };
+    *p.into_inner() += 1;
+    let Err(TryLockError::Poisoned(p)) = m.lock_spin(0) else { panic!("lock_spin") };
+    *p.into_inner() += 1;
+    let Err(TryLockError::Poisoned(p)) = m.lock_timeout(Duration::from_millis(10)) else {
+        panic!("lock_timeout")
+    };
+    *p.into_inner() += 1;
+    let Err(p) = m.lock_arc() else { panic!("lock_arc") };
+    *p.into_inner() += 1;
+
+    assert!(!m.is_locked());
+    assert_eq!(*m.lock().unwrap_or_else(PoisonError::into_inner), 4);
+
+    // Being taken is still WouldBlock, not Poisoned.
+    let _guard = m.lock_arc();
+    assert!(matches!(m.try_lock(), Err(TryLockError::WouldBlock)));
+    assert!(matches!(m.lock_spin(10), Err(TryLockError::WouldBlock)));
+    assert!(matches!(m.lock_timeout(Duration::from_millis(10)), Err(TryLockError::WouldBlock)));
+}
+
+#[test]
+fn test_is_locked() {
+    let m = Mutex::new(0);
+    assert!(!m.is_locked());
+    let guard = m.lock().unwrap();
+    assert!(m.is_locked());
+    drop(guard);
+    assert!(!m.is_locked());
+}
+
+#[test]
+fn test_with_lock() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::thread;
+
+    let m = Mutex::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    m.with_lock(|x| *x += 1);
+                }
+            });
+        }
+    });
+    assert_eq!(m.with_lock(|x| *x), 4000);
+
+    let result = catch_unwind(AssertUnwindSafe(|| m.with_lock(|_| panic!("oops"))));
+    assert!(result.is_err());
+    // Unlocked, but poisoned.
+    assert!(!m.is_locked());
+    assert!(m.is_poisoned());
+    let Err(TryLockError::Poisoned(poisoned)) = m.try_lock() else { panic!("should be poisoned") };
+    assert_eq!(*poisoned.into_inner(), 4000);
+}
+
+// Both ends of the limit: straight to the syscall, and spinning first.
+// How they compare in time is up to the machine, see bench.rs for that.
+#[test]
+fn test_spin_limit() {
+    use std::thread;
+
+    for spins in [0, 100] {
+        let m = Mutex::with_spin_limit(0, spins);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100_000 {
+                        *m.lock().unwrap() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(m.into_inner(), 400_000);
+    }
+}
+
+#[test]
+fn test_lock_spin() {
+    use std::sync::Barrier;
+    use std::thread;
+
+    let m = Mutex::new(0);
+    let locked = Barrier::new(2);
+    let checked = Barrier::new(2);
+    thread::scope(|s| {
+        s.spawn(|| {
+            let guard = m.lock().unwrap();
+            locked.wait();
+            checked.wait();
+            // Released quickly after the check.
+            drop(guard);
+        });
+        locked.wait();
+        // No spinning, same as try_lock.
+        assert!(matches!(m.lock_spin(0), Err(TryLockError::WouldBlock)));
+        checked.wait();
+        // With a single core the holder may need our time slice to get
+        // to the unlock, so we give it that between the attempts.
+        let mut attempts = 0;
+        let mut guard = loop {
+            if let Ok(guard) = m.lock_spin(1000) {
+                break guard;
+            }
+            attempts += 1;
+            assert!(attempts < 10_000);
+            thread::yield_now();
+        };
+        *guard += 1;
+    });
+    assert_eq!(*m.lock_spin(0).unwrap(), 1);
+}
+
+#[test]
+fn test_lock_arc() {
+    use std::thread;
+
+    let m = Arc::new(Mutex::new(vec![1]));
+    let mut guard = m.lock_arc().unwrap();
+    guard.push(2);
+    let t = thread::spawn(move || {
+        guard.push(3);
+        // Unlocked here, on the other thread.
+    });
+    t.join().unwrap();
+    assert!(!m.is_locked());
+    assert_eq!(*m.lock().unwrap(), [1, 2, 3]);
+
+    // The guard keeps the mutex alive on its own.
+    let guard = Arc::new(Mutex::new(5)).lock_arc().unwrap();
+    assert_eq!(*guard, 5);
+}
+
+#[test]
+fn test_arc_guard_sync() {
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+    assert_send::<ArcMutexGuard<Cell<u32>>>();
+    assert_sync::<ArcMutexGuard<u32>>();
+
+    // There's no T: !Sync bound, so this checks it the other way around.
+    // With a Sync type both impls would apply and the `_` below would be
+    // ambiguous, so this only compiles if the guard is not Sync.
+    trait AmbiguousIfSync<A> {
+        fn check() {}
+    }
+    impl<T: ?Sized> AmbiguousIfSync<()> for T {}
+    impl<T: ?Sized + Sync> AmbiguousIfSync<u8> for T {}
+    <ArcMutexGuard<Cell<u32>> as AmbiguousIfSync<_>>::check();
+}
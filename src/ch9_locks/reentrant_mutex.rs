@@ -0,0 +1,127 @@
+use crate::futex::wake_one;
+use super::mutex_3::{lock_contended, DEFAULT_SPIN_LIMIT};
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU32, AtomicU64};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// A mutex that the thread holding it can lock again, instead of deadlocking
+// on itself. Every lock needs its own unlock (a guard drop) and only the
+// last one lets other threads in.
+//
+// The guard gives out &T only. Two nested guards on the same thread would
+// otherwise be two &mut T to the same data. For mutation use a Cell or a
+// RefCell inside, same as with std's ReentrantLock.
+pub struct ReentrantMutex<T> {
+    /// Same as in mutex_3, and locked the same way.
+    /// 0: unlocked
+    /// 1: locked, no other threads waiting
+    /// 2: locked, other threads waiting
+    state: AtomicU32,
+    /// Id of the thread that holds the lock, 0 if nobody does.
+    owner: AtomicU64,
+    /// How many guards the owner has. Only the owner touches it.
+    count: Cell<u32>,
+    value: T,
+}
+
+unsafe impl<T> Sync for ReentrantMutex<T> where T: Send {}
+
+// std's ThreadId can't be turned into a number on stable Rust,
+// so each thread gets its own id on the first use.
+fn current_thread_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static ID: u64 = NEXT_ID.fetch_add(1, Relaxed);
+    }
+    ID.with(|id| *id)
+}
+
+pub struct ReentrantMutexGuard<'a, T> {
+    mutex: &'a ReentrantMutex<T>,
+    // The guard has to be dropped on the thread that owns the lock.
+    // Pointers are not Send, so this makes the guard not Send too.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> ReentrantMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            owner: AtomicU64::new(0),
+            count: Cell::new(0),
+            value,
+        }
+    }
+
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, T> {
+        let me = current_thread_id();
+        // Relaxed is enough. Only this thread could have stored its own id,
+        // and it would see its own store. Any other id, or a stale one, is
+        // just "not us", and then we go and lock for real.
+        if self.owner.load(Relaxed) == me {
+            let count = self.count.get().checked_add(1).expect("too many nested locks");
+            self.count.set(count);
+        } else {
+            if self.state.compare_exchange(0, 1, Acquire, Relaxed).is_err() {
+                lock_contended(&self.state, DEFAULT_SPIN_LIMIT);
+            }
+            self.owner.store(me, Relaxed);
+            self.count.set(1);
+        }
+        ReentrantMutexGuard { mutex: self, _not_send: PhantomData }
+    }
+}
+
+impl<T> Deref for ReentrantMutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.mutex.value
+    }
+}
+
+impl<T> Drop for ReentrantMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let count = self.mutex.count.get() - 1;
+        self.mutex.count.set(count);
+        if count == 0 {
+            self.mutex.owner.store(0, Relaxed);
+            if self.mutex.state.swap(0, Release) == 2 {
+                wake_one(&self.mutex.state);
+            }
+        }
+    }
+}
+
+#[test]
+fn nested_lock() {
+    use std::cell::RefCell;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
+
+    let m = ReentrantMutex::new(RefCell::new(Vec::new()));
+    let other_done = AtomicBool::new(false);
+    thread::scope(|s| {
+        let outer = m.lock();
+        outer.borrow_mut().push(1);
+        let inner = m.lock();
+        inner.borrow_mut().push(2);
+
+        s.spawn(|| {
+            m.lock().borrow_mut().push(3);
+            other_done.store(true, Relaxed);
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        drop(inner);
+        // Still held by the outer guard.
+        thread::sleep(Duration::from_millis(50));
+        assert!(!other_done.load(Relaxed));
+        outer.borrow_mut().push(4);
+        drop(outer);
+    });
+    assert!(other_done.load(Relaxed));
+    assert_eq!(*m.lock().borrow(), [1, 2, 4, 3]);
+}
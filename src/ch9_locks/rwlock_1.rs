@@ -2,11 +2,26 @@ use atomic_wait::{wait, wake_all, wake_one};
 use std::cell::UnsafeCell;
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release, SeqCst};
 
 pub struct RwLock<T> {
     /// The number of readers, or u32::MAX if write-locked.
     state: AtomicU32,
+    /// Incremented to wake up writers. Writers wait on it instead of the
+    /// state, so a reader leaving doesn't wake the readers blocked on the
+    /// state and a writer leaving wakes only one writer. Same as in rwlock_2.
+    writer_wake_counter: AtomicU32,
+    /// When set, new readers let waiting writers go first.
+    writer_preferred: bool,
+    /// The number of writers waiting for the lock. Only
+    /// tracked when writer_preferred is set.
+    writers_waiting: AtomicU32,
+    /// The number of readers sleeping on the state, waiting for a writer
+    /// to leave. A writer that sees 0 here skips its wake_all.
+    readers_waiting: AtomicU32,
+    /// How many times a writer did call wake_all on the state.
+    #[cfg(test)]
+    reader_wakes: AtomicU32,
     value: UnsafeCell<T>,
 }
 
@@ -21,11 +36,46 @@ impl<T> RwLock<T> {
     pub const fn new(value: T) -> Self {
         Self {
             state: AtomicU32::new(0), // Unlocked.
+            writer_wake_counter: AtomicU32::new(0),
+            writer_preferred: false,
+            writers_waiting: AtomicU32::new(0),
+            readers_waiting: AtomicU32::new(0),
+            #[cfg(test)]
+            reader_wakes: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    // With the plain new a writer can wait forever if readers keep coming:
+    // the state never drops to 0 while there is at least one of them.
+    // Here a new reader waits while any writer is waiting, so the readers
+    // that are already in leave eventually and the writer gets its turn.
+    //
+    // Now readers can starve if writers keep coming, that's the trade off.
+    pub const fn new_writer_preferred(value: T) -> Self {
+        Self {
+            state: AtomicU32::new(0),
+            writer_wake_counter: AtomicU32::new(0),
+            writer_preferred: true,
+            writers_waiting: AtomicU32::new(0),
+            readers_waiting: AtomicU32::new(0),
+            #[cfg(test)]
+            reader_wakes: AtomicU32::new(0),
             value: UnsafeCell::new(value),
         }
     }
 
     pub fn read(&self) -> ReadGuard<T> {
+        if self.writer_preferred {
+            loop {
+                let writers = self.writers_waiting.load(Relaxed);
+                if writers == 0 {
+                    break;
+                }
+                // The last waiting writer to get the lock wakes us up.
+                wait(&self.writers_waiting, writers);
+            }
+        }
         let mut s = self.state.load(Relaxed);
         loop {
             if s < u32::MAX {
@@ -39,19 +89,35 @@ impl<T> RwLock<T> {
             }
             if s == u32::MAX {
                 // Wait until the state changes from MAX that means writer-locked.
+                // SeqCst, see the writer's drop.
+                self.readers_waiting.fetch_add(1, SeqCst);
                 wait(&self.state, u32::MAX);
+                self.readers_waiting.fetch_sub(1, Relaxed);
                 s = self.state.load(Relaxed);
             }
         }
     }
 
     pub fn write(&self) -> WriteGuard<T> {
-        while let Err(state_value) = self.state.compare_exchange(
+        if self.writer_preferred {
+            self.writers_waiting.fetch_add(1, Relaxed);
+        }
+        while self.state.compare_exchange(
             0, u32::MAX, Acquire, Relaxed
-        ) {
-            // Wait while already locked.
-            // Wait untill the state_value changes.
-            wait(&self.state, state_value);
+        ).is_err() {
+            // Waiting on the state directly can lose a wakeup: it can go
+            // 1 -> 0 -> 1 between the failed compare_exchange and the wait,
+            // and then we sleep on a value that looks unchanged. The counter
+            // only grows, so any unlock after this load makes the wait return.
+            let writer_is_done_notification = self.writer_wake_counter.load(Acquire);
+            if self.state.load(Relaxed) != 0 {
+                wait(&self.writer_wake_counter, writer_is_done_notification);
+            }
+        }
+        if self.writer_preferred && self.writers_waiting.fetch_sub(1, Relaxed) == 1 {
+            // No writers are waiting anymore, let the readers through. They'll
+            // still wait for us on the state, since we hold the lock now.
+            wake_all(&self.writers_waiting);
         }
         WriteGuard { rwlock: self }
     }
@@ -80,12 +146,27 @@ impl<T> DerefMut for WriteGuard<'_, T> {
 // Unlocking done by a writer unlocks everybody
 impl<T> Drop for WriteGuard<'_, T> {
     fn drop(&mut self) {
-        self.rwlock.state.store(0, Release);
-        // Wake up all waiting readers and writers.
+        // SeqCst, so that the load of readers_waiting below can't be done
+        // before this store.
+        self.rwlock.state.store(0, SeqCst);
+        // Wake up one writer and all the waiting readers.
         //
-        // If we have competing readers and writers
+        // If we have competing readers and the writer
         // there would be a race with a random winner.
-        wake_all(&self.rwlock.state);
+        self.rwlock.writer_wake_counter.fetch_add(1, Release);
+        wake_one(&self.rwlock.writer_wake_counter);
+
+        // Most unlocks have no readers waiting, and then the wake_all is a
+        // syscall for nothing. The window where we skip it: a reader that
+        // saw MAX and is about to count itself. Both sides are SeqCst, so
+        // either we see its increment, or its futex wait comes after our
+        // store of 0 and returns right away. A reader that is counted but
+        // not asleep yet only costs us a wake_all that wakes nobody.
+        if self.rwlock.readers_waiting.load(SeqCst) > 0 {
+            #[cfg(test)]
+            self.rwlock.reader_wakes.fetch_add(1, Relaxed);
+            wake_all(&self.rwlock.state);
+        }
     }
 }
 
@@ -110,7 +191,8 @@ impl<T> Drop for ReadGuard<'_, T> {
             // But we can have a waiting writer that awaits
             // till the state is zero. We need to wake up that
             // writer for it to recheck the state and wake up.
-            wake_one(&self.rwlock.state);
+            self.rwlock.writer_wake_counter.fetch_add(1, Release);
+            wake_one(&self.rwlock.writer_wake_counter);
         }
     }
 }
@@ -119,4 +201,86 @@ impl<T> Drop for ReadGuard<'_, T> {
 // Acquire - wait for all the pending operations to complete
 // They work only when they are used cooperatively
 //
-// Relaxed - ok to retry in the outer loop
\ No newline at end of file
+// Relaxed - ok to retry in the outer loop
+#[test]
+fn writer_preferred() {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    let lock = RwLock::new_writer_preferred(0);
+    let written = AtomicBool::new(false);
+    thread::scope(|s| {
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut iterations = 0;
+                    while !written.load(Relaxed) && iterations < 1_000_000 {
+                        let guard = lock.read();
+                        std::hint::black_box(*guard);
+                        thread::yield_now();
+                        drop(guard);
+                        iterations += 1;
+                    }
+                    iterations
+                })
+            })
+            .collect();
+        s.spawn(|| {
+            *lock.write() += 1;
+            written.store(true, Relaxed);
+        });
+        for reader in readers {
+            assert!(reader.join().unwrap() < 1_000_000);
+        }
+    });
+    assert_eq!(*lock.read(), 1);
+}
+
+#[test]
+fn mixed_stress() {
+    use std::thread;
+
+    for lock in [RwLock::new(0), RwLock::new_writer_preferred(0)] {
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        assert!(*lock.read() <= 20_000);
+                    }
+                });
+            }
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..10_000 {
+                        *lock.write() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.read(), 20_000);
+    }
+}
+
+#[test]
+fn uncontended_write_skips_wake() {
+    use std::thread;
+    use std::time::Duration;
+
+    let lock = RwLock::new(0);
+    for _ in 0..1000 {
+        *lock.write() += 1;
+        drop(lock.read());
+    }
+    assert_eq!(lock.reader_wakes.load(Relaxed), 0);
+
+    // A reader blocked on a writer still gets woken up.
+    thread::scope(|s| {
+        let mut guard = lock.write();
+        s.spawn(|| assert_eq!(*lock.read(), 1001));
+        while lock.readers_waiting.load(Relaxed) == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        *guard += 1;
+    });
+    assert_eq!(lock.reader_wakes.load(Relaxed), 1);
+}
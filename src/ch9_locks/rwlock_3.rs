@@ -1,8 +1,10 @@
-use atomic_wait::{wait, wake_all, wake_one};
+use crate::futex::{wait, wait_timeout, wake_all, wake_one};
 use std::cell::UnsafeCell;
+use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic::AtomicU32;
 use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::time::{Duration, Instant};
 
 pub struct RwLock<T> {
     /// The number of read locks times two, plus one if there's
@@ -17,28 +19,56 @@ pub struct RwLock<T> {
     /// They all would just block on the same state value
     /// and unblock only other writers until there is
     /// no other writer left.
+    ///
+    /// The highest bit is set while an upgradeable reader holds
+    /// the lock, or a reader is upgrading in with_upgraded. It's
+    /// the one and only upgrade token, so there is never more than
+    /// one thread waiting for the others to leave. It doesn't count
+    /// as a reader and doesn't change the parity, so plain readers
+    /// come and go as usual. But any state with that bit is above 1,
+    /// so writers have to wait. The number of readers is capped below
+    /// that bit, that keeps u32::MAX unambiguous.
     state: AtomicU32,
     /// Incremented to wake up writers.
     writer_wake_counter: AtomicU32,
+    /// Writers take a ticket from here when they arrive...
+    writer_next_ticket: AtomicU32,
+    /// ...and wait until their ticket is served. Only the writer
+    /// being served competes for the state.
+    writer_now_serving: AtomicU32,
     value: UnsafeCell<T>,
 }
 
 unsafe impl<T> Sync for RwLock<T> where T: Send + Sync {}
 
+const UPGRADEABLE: u32 = 1 << 31;
+
 impl<T> RwLock<T> {
     pub const fn new(value: T) -> Self {
         Self {
             state: AtomicU32::new(0),
             writer_wake_counter: AtomicU32::new(0),
+            writer_next_ticket: AtomicU32::new(0),
+            writer_now_serving: AtomicU32::new(0),
             value: UnsafeCell::new(value),
         }
     }
 
+    // Owning the lock or borrowing it exclusively means nobody else can
+    // hold it, so there is no need to lock. The atomics are not touched.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
     pub fn read(&self) -> ReadGuard<T> {
         let mut s = self.state.load(Relaxed);
         loop {
             if s % 2 == 0 { // Even.
-                assert!(s < u32::MAX - 2, "too many readers");
+                assert!(s & !UPGRADEABLE < UPGRADEABLE - 4, "too many readers");
                 match self.state.compare_exchange_weak(
                     s, s + 2, Acquire, Relaxed
                 ) {
@@ -54,6 +84,35 @@ impl<T> RwLock<T> {
     }
 
     pub fn write(&self) -> WriteGuard<T> {
+        // Writers line up first, in the order they arrived. A writer waits
+        // here until it is at the head of the line. Each one waits for its
+        // own ticket number, but the futex can't wake a specific waiter, so
+        // all of them are woken and the rest go back to sleep.
+        //
+        // Wrapping is fine, tickets are only compared for equality.
+        let ticket = self.writer_next_ticket.fetch_add(1, Relaxed);
+        loop {
+            let serving = self.writer_now_serving.load(Acquire);
+            if serving == ticket {
+                break;
+            }
+            wait(&self.writer_now_serving, serving);
+        }
+
+        // The head of the line lets the next writer in as soon as it holds
+        // the lock, not when it unlocks. The next one would then mark the
+        // state odd and wait, which keeps new readers from getting ahead of it.
+        struct NextWriter<'a>(&'a AtomicU32);
+
+        impl Drop for NextWriter<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Release);
+                wake_all(self.0);
+            }
+        }
+
+        let _next = NextWriter(&self.writer_now_serving);
+
         let mut state = self.state.load(Relaxed);
         loop {
             // If we see it as unlocked, try to get the exclusive access to the data
@@ -71,9 +130,8 @@ impl<T> RwLock<T> {
             // wait returned. Or it could be a new writer that would also observe
             // 1 and would compete with the old writer in the compare_exchange.
             //
-            // Looks like the writers would race. If that's a problem, there should
-            // be a queue implemented for the writers. Writers would be waked up
-            // in the order they arrived.
+            // They can't race though since only the head of the writer line gets
+            // here. The ones that arrived later are still waiting for their ticket.
             if state <= 1 {
                 match self.state.compare_exchange(
                     state, u32::MAX, Acquire, Relaxed
@@ -111,6 +169,182 @@ impl<T> RwLock<T> {
             }
         }
     }
+
+    // A reader that may later become the writer. Plain readers can share the
+    // lock with it, but there can be only one of it at a time. Otherwise two
+    // of them could try to upgrade and each would wait for the other to go.
+    //
+    // It waits for writers same as a plain reader does.
+    pub fn upgradeable_read(&self) -> UpgradeableReadGuard<'_, T> {
+        let mut s = self.state.load(Relaxed);
+        loop {
+            if s & 1 == 0 && s & UPGRADEABLE == 0 {
+                match self.state.compare_exchange_weak(s, s | UPGRADEABLE, Acquire, Relaxed) {
+                    Ok(_) => return UpgradeableReadGuard { rwlock: self },
+                    Err(e) => { s = e; continue; }
+                }
+            }
+            // Woken by a writer or by the other upgradeable reader when they are done.
+            wait(&self.state, s);
+            s = self.state.load(Relaxed);
+        }
+    }
+
+    // These are for diagnostics and assertions only. They are a single load
+    // and the answer can be out of date right away, since other threads keep
+    // locking and unlocking.
+    //
+    // A waiting writer alone (the state is 1) doesn't count as locked,
+    // nobody holds the lock at that moment.
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Relaxed) > 1
+    }
+
+    pub fn is_write_locked(&self) -> bool {
+        self.state.load(Relaxed) == u32::MAX
+    }
+
+    // Plain readers only, an upgradeable reader is not counted.
+    pub fn reader_count(&self) -> u32 {
+        match self.state.load(Relaxed) {
+            u32::MAX => 0,
+            s => (s & !UPGRADEABLE) / 2,
+        }
+    }
+
+    // Never waits. Fails only when the state is odd - write locked or a
+    // writer is waiting. Another reader coming or going between our load
+    // and compare_exchange is not contention for us, so we just retry then.
+    pub fn try_read(&self) -> Option<ReadGuard<'_, T>> {
+        let mut s = self.state.load(Relaxed);
+        while s & 1 == 0 {
+            assert!(s & !UPGRADEABLE < UPGRADEABLE - 4, "too many readers");
+            match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
+                Ok(_) => return Some(ReadGuard { rwlock: self }),
+                Err(e) => s = e,
+            }
+        }
+        None
+    }
+
+    // Never waits and never marks itself as a waiting writer,
+    // so failing here doesn't block anyone else.
+    //
+    // Doesn't cut in line either: if writers are lined up, it fails.
+    pub fn try_write(&self) -> Option<WriteGuard<'_, T>> {
+        let s = self.state.load(Relaxed);
+        let no_line = self.writer_next_ticket.load(Relaxed) == self.writer_now_serving.load(Relaxed);
+        if s <= 1 && no_line && self.state.compare_exchange(s, u32::MAX, Acquire, Relaxed).is_ok() {
+            Some(WriteGuard { rwlock: self })
+        } else {
+            None
+        }
+    }
+
+    // Same as read, but gives up at the deadline. A reader never changes
+    // the state while it waits, so there is nothing to undo on a timeout.
+    pub fn try_read_for(&self, timeout: Duration) -> Option<ReadGuard<'_, T>> {
+        let deadline = Instant::now() + timeout;
+        let mut s = self.state.load(Relaxed);
+        loop {
+            if s & 1 == 0 {
+                assert!(s & !UPGRADEABLE < UPGRADEABLE - 4, "too many readers");
+                match self.state.compare_exchange_weak(s, s + 2, Acquire, Relaxed) {
+                    Ok(_) => return Some(ReadGuard { rwlock: self }),
+                    Err(e) => { s = e; continue; }
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            wait_timeout(&self.state, s, deadline - now);
+            s = self.state.load(Relaxed);
+        }
+    }
+
+    // Same as write, but gives up at the deadline.
+    //
+    // It doesn't take a ticket. A ticket can't be given back: the writers
+    // behind it would wait for it to be served forever. Instead it waits for
+    // the line to be empty, same as try_write doesn't cut in. Then it
+    // competes for the state like the head of the line does.
+    //
+    // On a timeout the odd bit it may have set has to go, or new readers
+    // would keep waiting for a writer that is not there anymore. But another
+    // writer (or an upgrade) could have seen the bit already set and now be
+    // relying on it. So after clearing it everybody who could be waiting
+    // for it is woken up, and whoever still needs the bit sets it again.
+    pub fn try_write_for(&self, timeout: Duration) -> Option<WriteGuard<'_, T>> {
+        let deadline = Instant::now() + timeout;
+        let mut marked = false;
+        // Set once we have slept on the counter. A wake_one there could
+        // have been meant for a writer from the line that got in meanwhile,
+        // and if we don't go for the lock ourselves, we pass it on.
+        let mut woken_on_counter = false;
+        loop {
+            let serving = self.writer_now_serving.load(Acquire);
+            let state = self.state.load(Relaxed);
+            if self.writer_next_ticket.load(Relaxed) == serving {
+                if state <= 1 {
+                    if self.state.compare_exchange(state, u32::MAX, Acquire, Relaxed).is_ok() {
+                        return Some(WriteGuard { rwlock: self });
+                    }
+                    continue;
+                }
+                // Block new readers, same as in write.
+                if state & 1 == 0 {
+                    if self.state.compare_exchange(state, state + 1, Relaxed, Relaxed).is_err() {
+                        continue;
+                    }
+                    marked = true;
+                }
+            }
+
+            let writer_is_done_notification = self.writer_wake_counter.load(Acquire);
+            let now = Instant::now();
+            if now >= deadline {
+                if marked {
+                    self.unmark_writer();
+                }
+                if woken_on_counter {
+                    wake_one(&self.writer_wake_counter);
+                }
+                return None;
+            }
+            if self.writer_next_ticket.load(Relaxed) != serving {
+                // The last reader wakes only one writer. If that was us, the
+                // head of the line would sleep on with the lock free.
+                if woken_on_counter {
+                    wake_one(&self.writer_wake_counter);
+                    woken_on_counter = false;
+                }
+                // Wait for the head of the line to get the lock.
+                wait_timeout(&self.writer_now_serving, serving, deadline - now);
+            } else if self.state.load(Relaxed) >= 2 {
+                wait_timeout(&self.writer_wake_counter, writer_is_done_notification, deadline - now);
+                woken_on_counter = true;
+            }
+        }
+    }
+
+    // Clears the odd bit of a writer that gave up waiting.
+    fn unmark_writer(&self) {
+        let cleared = self.state.fetch_update(Relaxed, Relaxed, |s| {
+            // Locked by a writer, or someone has already cleared it.
+            if s == u32::MAX || s & 1 == 0 {
+                None
+            } else {
+                Some(s - 1)
+            }
+        });
+        if cleared.is_ok() {
+            // Writers wait on the counter, readers and upgrades on the state.
+            self.writer_wake_counter.fetch_add(1, Release);
+            wake_all(&self.writer_wake_counter);
+            wake_all(&self.state);
+        }
+    }
 }
 
 pub struct ReadGuard<'a, T> {
@@ -141,16 +375,210 @@ impl<T> Deref for ReadGuard<'_, T> {
     }
 }
 
+impl<T> ReadGuard<'_, T> {
+    // Gives exclusive access to the data for the duration of the closure,
+    // without letting go of the read lock. Any other readers need to leave
+    // first, so this blocks until they do.
+    //
+    // Two threads upgrading at the same time would deadlock: each of them
+    // waits for the other one to stop reading. That's why only the holder
+    // of the UPGRADEABLE bit can upgrade, the same bit an upgradeable_read
+    // holds. With a token of its own, this and an UpgradeableReadGuard::upgrade
+    // would each have one and wait for the other forever.
+    //
+    // A reader that doesn't get the bit has to step aside - it gives up its
+    // read lock until the bit is free (otherwise the holder would wait for
+    // it forever) and reads again afterwards. So in that case the data can
+    // change between the read and the closure. The closure gets &mut T and
+    // should look at the data again instead of relying on what it has seen
+    // before the upgrade.
+    pub fn with_upgraded<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> R {
+        let rwlock = self.rwlock;
+
+        let mut s = rwlock.state.load(Relaxed);
+        loop {
+            // We hold a read lock, so the state can't be u32::MAX here.
+            if s & UPGRADEABLE == 0 {
+                match rwlock.state.compare_exchange_weak(s, s | UPGRADEABLE, Acquire, Relaxed) {
+                    Ok(_) => break,
+                    Err(e) => { s = e; continue; }
+                }
+            }
+            // Same as ReadGuard::drop. If we were the last plain reader
+            // next to an upgradeable one, that wakes it up.
+            read_unlock(&rwlock.state, &rwlock.writer_wake_counter);
+            loop {
+                // Whoever clears the bit (an upgradeable reader leaving, the
+                // writer it upgraded to, or another with_upgraded) wakes the
+                // state.
+                let s = rwlock.state.load(Relaxed);
+                if s != u32::MAX && s & UPGRADEABLE == 0 {
+                    break;
+                }
+                wait(&rwlock.state, s);
+            }
+            // Take the read lock back, but keep using our own guard for it.
+            std::mem::forget(rwlock.read());
+            s = rwlock.state.load(Relaxed);
+        }
+
+        // We are still a reader, so besides the bit the state is at least 2.
+        // It would be 2 when we are the only reader, or 3 if there is also a
+        // writer waiting.
+        //
+        // Readers don't signal anyone when they leave unless they are the last
+        // one, and we'll never be the last one while holding our read lock.
+        // So we just yield until they are gone. Upgrades are expected to be
+        // rare and short, otherwise one should have taken a write lock.
+        let mut s = s | UPGRADEABLE;
+        loop {
+            if s & !1 == UPGRADEABLE | 2 {
+                match rwlock.state.compare_exchange_weak(s, u32::MAX, Acquire, Relaxed) {
+                    Ok(_) => break,
+                    Err(e) => { s = e; continue; }
+                }
+            }
+            std::thread::yield_now();
+            s = rwlock.state.load(Relaxed);
+        }
+
+        // Going back to the read lock is done in a drop,
+        // so it also happens if the closure panics.
+        struct Downgrade<'a, T> {
+            rwlock: &'a RwLock<T>,
+        }
+
+        impl<T> Drop for Downgrade<'_, T> {
+            fn drop(&mut self) {
+                // Back to one plain reader, and the UPGRADEABLE bit is given
+                // back with it. The writer waiting bit isn't restored: the
+                // writer that set it could have timed out while we were
+                // exclusive, and its unmark_writer can't clear a u32::MAX.
+                // A stale bit with nobody to clear it would keep all the
+                // readers out for good. Same as WriteGuard::downgrade, the
+                // writers that are still there are woken and mark it again.
+                self.rwlock.state.store(2, Release);
+                self.rwlock.writer_wake_counter.fetch_add(1, Release);
+                wake_one(&self.rwlock.writer_wake_counter);
+                wake_all(&self.rwlock.state);
+            }
+        }
+
+        let _downgrade = Downgrade { rwlock };
+        // Safety: The state is u32::MAX, so nobody else has access to the data.
+        f(unsafe { &mut *rwlock.value.get() })
+    }
+}
+
+impl<'a, T> WriteGuard<'a, T> {
+    // Turns the write lock into a read lock without unlocking in between,
+    // so no writer can get in. We go straight from u32::MAX to 2 (one reader)
+    // and wake the readers that waited for the writer to finish.
+    //
+    // Waiting writers have to be woken too, even though they can't get in.
+    // While we were write locked they couldn't mark themselves in the state,
+    // so the odd bit is lost. A writer that keeps sleeping on the counter
+    // would never be woken since the last reader only does that when it
+    // sees the odd bit. Woken up, it sets the bit again and goes back to
+    // sleep, and from that moment new readers wait for it as usual.
+    pub fn downgrade(guard: Self) -> ReadGuard<'a, T> {
+        let rwlock = guard.rwlock;
+        std::mem::forget(guard);
+        // Release since the data written under the write lock
+        // is published to the readers we are letting in.
+        rwlock.state.store(2, Release);
+        wake_all(&rwlock.state);
+        rwlock.writer_wake_counter.fetch_add(1, Release);
+        wake_one(&rwlock.writer_wake_counter);
+        ReadGuard { rwlock }
+    }
+}
+
 impl<T> Drop for ReadGuard<'_, T> {
     fn drop(&mut self) {
-        // Decrement the state by 2 to remove one read-lock.
-        if self.rwlock.state.fetch_sub(2, Release) == 3 {
-            // If we decremented from 3 to 1, that means
-            // the RwLock is now unlocked _and_ there is
-            // a waiting writer, which we wake up.
+        read_unlock(&self.rwlock.state, &self.rwlock.writer_wake_counter);
+    }
+}
+
+// The unlocking is done with free functions that only need the atomics,
+// so that the mapped guards (that don't know T) can unlock too.
+fn read_unlock(state: &AtomicU32, writer_wake_counter: &AtomicU32) {
+    // Decrement the state by 2 to remove one read-lock.
+    match state.fetch_sub(2, Release) {
+        // If we decremented from 3 to 1, that means
+        // the RwLock is now unlocked _and_ there is
+        // a waiting writer, which we wake up.
+        3 => {
+            writer_wake_counter.fetch_add(1, Release);
+            wake_one(writer_wake_counter);
+        }
+        // We were the last plain reader next to an upgradeable one
+        // that may be waiting in upgrade. It waits on the state.
+        s if s == UPGRADEABLE | 3 => wake_all(state),
+        _ => {}
+    }
+}
+
+fn write_unlock(state: &AtomicU32, writer_wake_counter: &AtomicU32) {
+    state.store(0, Release);
+    writer_wake_counter.fetch_add(1, Release);
+    wake_one(writer_wake_counter);
+    wake_all(state);
+}
+
+pub struct UpgradeableReadGuard<'a, T> {
+    rwlock: &'a RwLock<T>,
+}
+
+impl<T> Deref for UpgradeableReadGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.value.get() }
+    }
+}
+
+impl<'a, T> UpgradeableReadGuard<'a, T> {
+    // Waits for the plain readers to leave and takes the write lock. There is
+    // no other upgradeable reader and no writer could have got in, so the data
+    // is still the same as what we have read.
+    pub fn upgrade(guard: Self) -> WriteGuard<'a, T> {
+        let rwlock = guard.rwlock;
+        // The flag stays set until the state becomes u32::MAX.
+        std::mem::forget(guard);
+        let mut s = rwlock.state.load(Relaxed);
+        loop {
+            // No plain readers left, maybe a writer waiting.
+            if s & !1 == UPGRADEABLE {
+                match rwlock.state.compare_exchange(s, u32::MAX, Acquire, Relaxed) {
+                    Ok(_) => return WriteGuard { rwlock },
+                    Err(e) => { s = e; continue; }
+                }
+            }
+            // Same as a writer, block new readers or they could keep us
+            // waiting forever.
+            if s & 1 == 0 {
+                match rwlock.state.compare_exchange(s, s + 1, Relaxed, Relaxed) {
+                    Ok(_) => s += 1,
+                    Err(e) => { s = e; continue; }
+                }
+            }
+            // The last reader wakes us up, see ReadGuard::drop.
+            wait(&rwlock.state, s);
+            s = rwlock.state.load(Relaxed);
+        }
+    }
+}
+
+impl<T> Drop for UpgradeableReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let s = self.rwlock.state.fetch_and(!UPGRADEABLE, Release) & !UPGRADEABLE;
+        if s == 1 {
+            // No readers left and a writer is waiting for us.
             self.rwlock.writer_wake_counter.fetch_add(1, Release);
             wake_one(&self.rwlock.writer_wake_counter);
         }
+        // Another upgradeable reader could be waiting for us on the state.
+        wake_all(&self.rwlock.state);
     }
 }
 
@@ -166,9 +594,556 @@ impl<T> Drop for ReadGuard<'_, T> {
 // And we don't need to wake all the readers in this case either.
 impl<T> Drop for WriteGuard<'_, T> {
     fn drop(&mut self) {
-        self.rwlock.state.store(0, Release);
-        self.rwlock.writer_wake_counter.fetch_add(1, Release);
-        wake_one(&self.rwlock.writer_wake_counter);
-        wake_all(&self.rwlock.state);
+        write_unlock(&self.rwlock.state, &self.rwlock.writer_wake_counter);
     }
 }
+
+// Guards that keep the lock but only give access to a part of the data,
+// like MappedMutexGuard in mutex_3. They keep the two atomics needed to
+// unlock, the projected value is a pointer. PhantomData stands for the
+// reference we act as if we had, so the borrow can't outlive the lock.
+pub struct MappedReadGuard<'a, U> {
+    state: &'a AtomicU32,
+    writer_wake_counter: &'a AtomicU32,
+    value: *const U,
+    _marker: PhantomData<&'a U>,
+}
+
+pub struct MappedWriteGuard<'a, U> {
+    state: &'a AtomicU32,
+    writer_wake_counter: &'a AtomicU32,
+    value: *mut U,
+    _marker: PhantomData<&'a mut U>,
+}
+
+unsafe impl<U> Sync for MappedReadGuard<'_, U> where U: Sync {}
+unsafe impl<U> Sync for MappedWriteGuard<'_, U> where U: Sync {}
+
+impl<'a, T> ReadGuard<'a, T> {
+    // Associated functions, so they don't shadow a `map` of T through Deref.
+    pub fn map<U, F: FnOnce(&T) -> &U>(guard: Self, f: F) -> MappedReadGuard<'a, U> {
+        // If f panics the guard is still here to unlock.
+        let value: *const U = f(&guard);
+        let rwlock = guard.rwlock;
+        std::mem::forget(guard);
+        MappedReadGuard {
+            state: &rwlock.state,
+            writer_wake_counter: &rwlock.writer_wake_counter,
+            value,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T> WriteGuard<'a, T> {
+    pub fn map<U, F: FnOnce(&mut T) -> &mut U>(mut guard: Self, f: F) -> MappedWriteGuard<'a, U> {
+        let value: *mut U = f(&mut guard);
+        let rwlock = guard.rwlock;
+        std::mem::forget(guard);
+        MappedWriteGuard {
+            state: &rwlock.state,
+            writer_wake_counter: &rwlock.writer_wake_counter,
+            value,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<U> Deref for MappedReadGuard<'_, U> {
+    type Target = U;
+    fn deref(&self) -> &U {
+        unsafe { &*self.value }
+    }
+}
+
+impl<U> Deref for MappedWriteGuard<'_, U> {
+    type Target = U;
+    fn deref(&self) -> &U {
+        unsafe { &*self.value }
+    }
+}
+
+impl<U> DerefMut for MappedWriteGuard<'_, U> {
+    fn deref_mut(&mut self) -> &mut U {
+        unsafe { &mut *self.value }
+    }
+}
+
+impl<U> Drop for MappedReadGuard<'_, U> {
+    fn drop(&mut self) {
+        read_unlock(self.state, self.writer_wake_counter);
+    }
+}
+
+impl<U> Drop for MappedWriteGuard<'_, U> {
+    fn drop(&mut self) {
+        write_unlock(self.state, self.writer_wake_counter);
+    }
+}
+
+// Locking several rwlocks one by one is a classic way to get a deadlock:
+// thread A write-locks x then y, thread B write-locks y then x, and each
+// of them ends up waiting for the lock the other one holds.
+//
+// The fix is to always lock in the same global order. Addresses are unique
+// and stable while the locks are borrowed, so we sort by them. The guards are
+// still returned in the order the caller passed the locks in.
+//
+// Passing the same lock twice would deadlock the thread on itself (for readers
+// too, since a waiting writer blocks new readers), so we panic instead.
+pub fn read_all<'a, T>(locks: &[&'a RwLock<T>]) -> Vec<ReadGuard<'a, T>> {
+    let mut guards: Vec<Option<ReadGuard<'a, T>>> = locks.iter().map(|_| None).collect();
+    for i in lock_order(locks) {
+        guards[i] = Some(locks[i].read());
+    }
+    guards.into_iter().map(Option::unwrap).collect()
+}
+
+pub fn write_all<'a, T>(locks: &[&'a RwLock<T>]) -> Vec<WriteGuard<'a, T>> {
+    let mut guards: Vec<Option<WriteGuard<'a, T>>> = locks.iter().map(|_| None).collect();
+    for i in lock_order(locks) {
+        guards[i] = Some(locks[i].write());
+    }
+    guards.into_iter().map(Option::unwrap).collect()
+}
+
+// Indexes into `locks` sorted by the address of the lock.
+fn lock_order<T>(locks: &[&RwLock<T>]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..locks.len()).collect();
+    order.sort_by_key(|&i| locks[i] as *const RwLock<T>);
+    for pair in order.windows(2) {
+        assert!(
+            !std::ptr::eq(locks[pair[0]], locks[pair[1]]),
+            "the same lock is passed more than once"
+        );
+    }
+    order
+}
+
+#[test]
+fn write_all_opposite_order() {
+    use std::thread;
+
+    let x = RwLock::new(0);
+    let y = RwLock::new(0);
+
+    thread::scope(|s| {
+        s.spawn(|| {
+            for _ in 0..10_000 {
+                let mut guards = write_all(&[&x, &y]);
+                *guards[0] += 1;
+                *guards[1] += 1;
+            }
+        });
+        s.spawn(|| {
+            for _ in 0..10_000 {
+                let mut guards = write_all(&[&y, &x]);
+                *guards[0] += 1;
+                *guards[1] += 1;
+            }
+        });
+        s.spawn(|| {
+            for _ in 0..10_000 {
+                // Both values are always updated together.
+                let guards = read_all(&[&x, &y]);
+                assert_eq!(*guards[0], *guards[1]);
+            }
+        });
+    });
+
+    assert_eq!(*x.read(), 20_000);
+    assert_eq!(*y.read(), 20_000);
+}
+
+#[test]
+fn with_upgraded() {
+    use std::thread;
+
+    let lock = RwLock::new((0, 0));
+
+    thread::scope(|s| {
+        // Two upgraders, so the UPGRADEABLE bit is contended too.
+        for _ in 0..2 {
+            s.spawn(|| {
+                for _ in 0..1_000 {
+                    let mut guard = lock.read();
+                    assert_eq!(guard.0, guard.1);
+                    guard.with_upgraded(|v| {
+                        v.0 += 1;
+                        v.1 += 1;
+                    });
+                    // Still reading, nobody could have changed it since.
+                    let after = *guard;
+                    assert_eq!(after.0, after.1);
+                    assert_eq!(*guard, after);
+                }
+            });
+        }
+        // Plain readers never see a half done update.
+        for _ in 0..2 {
+            s.spawn(|| {
+                for _ in 0..1_000 {
+                    let guard = lock.read();
+                    assert_eq!(guard.0, guard.1);
+                }
+            });
+        }
+    });
+
+    assert_eq!(*lock.read(), (2_000, 2_000));
+}
+
+#[test]
+fn try_read_write() {
+    use std::sync::Barrier;
+    use std::thread;
+
+    let lock = RwLock::new(0);
+    let locked = Barrier::new(2);
+    let checked = Barrier::new(2);
+    thread::scope(|s| {
+        s.spawn(|| {
+            let mut guard = lock.write();
+            locked.wait();
+            checked.wait();
+            *guard += 1;
+        });
+        locked.wait();
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        checked.wait();
+    });
+
+    // Readers share, but keep a writer out.
+    let r1 = lock.try_read().unwrap();
+    let r2 = lock.try_read().unwrap();
+    assert_eq!(*r1 + *r2, 2);
+    assert!(lock.try_write().is_none());
+    drop((r1, r2));
+
+    *lock.try_write().unwrap() += 1;
+    assert_eq!(*lock.read(), 2);
+}
+
+#[test]
+fn downgrade() {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    let lock = RwLock::new(0);
+    let written = AtomicBool::new(false);
+    thread::scope(|s| {
+        let mut guard = lock.write();
+        *guard = 1;
+
+        // This reader waits for the writer and gets in after the downgrade.
+        let (read, wait_read) = mpsc::channel();
+        let lock = &lock;
+        let reader = s.spawn(move || {
+            let guard = lock.read();
+            read.send(*guard).unwrap();
+            thread::sleep(Duration::from_millis(100));
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let guard = WriteGuard::downgrade(guard);
+        assert_eq!(wait_read.recv().unwrap(), 1);
+
+        // A writer waits while both readers are there.
+        let writer = s.spawn(|| {
+            *lock.write() = 2;
+            written.store(true, Relaxed);
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!written.load(Relaxed));
+        assert_eq!(*guard, 1);
+
+        drop(guard);
+        reader.join().unwrap();
+        writer.join().unwrap();
+    });
+    assert!(written.load(Relaxed));
+    assert_eq!(*lock.read(), 2);
+}
+
+#[test]
+fn upgradeable_read() {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Duration;
+
+    let lock = RwLock::new(0);
+    let upgraded = AtomicBool::new(false);
+    thread::scope(|s| {
+        let upgradeable = lock.upgradeable_read();
+        let r1 = lock.read();
+        let r2 = lock.try_read().unwrap();
+        assert_eq!(*upgradeable + *r1 + *r2, 0);
+        // Writers stay out while the upgradeable reader is there.
+        assert!(lock.try_write().is_none());
+
+        // Only one upgradeable reader at a time.
+        let second = s.spawn(|| *lock.upgradeable_read());
+
+        s.spawn(|| {
+            let mut guard = UpgradeableReadGuard::upgrade(upgradeable);
+            upgraded.store(true, Relaxed);
+            *guard += 1;
+        });
+        thread::sleep(Duration::from_millis(50));
+        // Still waiting for the plain readers.
+        assert!(!upgraded.load(Relaxed));
+        assert!(!second.is_finished());
+
+        drop(r1);
+        drop(r2);
+        // The second one gets in after the upgraded writer is done.
+        assert_eq!(second.join().unwrap(), 1);
+    });
+    assert!(upgraded.load(Relaxed));
+    assert_eq!(*lock.read(), 1);
+}
+
+#[test]
+fn upgrade_mixed_with_with_upgraded() {
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    // The upgradeable reader comes first, then a plain reader wants to
+    // upgrade in with_upgraded, and then the first one upgrades. Both
+    // need the other to go, only one of them can hold the bit.
+    let lock = RwLock::new(0);
+    let both_in = Barrier::new(2);
+    thread::scope(|s| {
+        s.spawn(|| {
+            let upgradeable = lock.upgradeable_read();
+            both_in.wait();
+            // Give the other one time to get to with_upgraded.
+            thread::sleep(Duration::from_millis(50));
+            *UpgradeableReadGuard::upgrade(upgradeable) += 1;
+        });
+        s.spawn(|| {
+            both_in.wait();
+            let mut guard = lock.read();
+            guard.with_upgraded(|v| *v += 1);
+        });
+    });
+    assert_eq!(*lock.read(), 2);
+
+    // And the two paths over and over, together with plain readers.
+    thread::scope(|s| {
+        for _ in 0..2 {
+            s.spawn(|| {
+                for _ in 0..500 {
+                    lock.read().with_upgraded(|v| *v += 1);
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..500 {
+                    *UpgradeableReadGuard::upgrade(lock.upgradeable_read()) += 1;
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..500 {
+                    std::hint::black_box(*lock.read());
+                }
+            });
+        }
+    });
+    // Nothing is left behind, not the bit and not a waiting writer.
+    let guard = lock.read();
+    assert_eq!(*guard, 2002);
+    assert_eq!(lock.state.load(Relaxed), 2);
+}
+
+#[test]
+fn writer_not_starved() {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    let lock = RwLock::new(0);
+    let written = AtomicBool::new(false);
+    thread::scope(|s| {
+        let readers: Vec<_> = (0..8)
+            .map(|_| {
+                s.spawn(|| {
+                    // Readers overlap all the time, so without blocking new
+                    // readers the state would never drop down to 1.
+                    let mut iterations = 0;
+                    while !written.load(Relaxed) && iterations < 1_000_000 {
+                        let guard = lock.read();
+                        std::hint::black_box(*guard);
+                        thread::yield_now();
+                        drop(guard);
+                        iterations += 1;
+                    }
+                    iterations
+                })
+            })
+            .collect();
+        s.spawn(|| {
+            for _ in 0..100 {
+                *lock.write() += 1;
+            }
+            written.store(true, Relaxed);
+        });
+        for reader in readers {
+            assert!(reader.join().unwrap() < 1_000_000);
+        }
+    });
+    assert!(written.load(Relaxed));
+
+    // Several writers line up and all of them get through.
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..1000 {
+                    *lock.write() += 1;
+                }
+            });
+        }
+    });
+    assert_eq!(*lock.read(), 4100);
+}
+
+#[test]
+fn is_locked() {
+    let lock = RwLock::new(0);
+    assert!(!lock.is_locked());
+
+    let r1 = lock.read();
+    let r2 = lock.read();
+    assert!(lock.is_locked());
+    assert!(!lock.is_write_locked());
+    assert_eq!(lock.reader_count(), 2);
+    drop((r1, r2));
+    assert!(!lock.is_locked());
+
+    let w = lock.write();
+    assert!(lock.is_locked());
+    assert!(lock.is_write_locked());
+    assert_eq!(lock.reader_count(), 0);
+    drop(w);
+    assert!(!lock.is_locked());
+}
+
+#[test]
+fn map_guards() {
+    let lock = RwLock::new((String::from("name"), vec![1, 2, 3]));
+
+    let name = ReadGuard::map(lock.read(), |pair| &pair.0);
+    let also_reading = lock.try_read().unwrap();
+    assert_eq!(*name, "name");
+    assert!(lock.try_write().is_none());
+    drop((name, also_reading));
+
+    let mut second = WriteGuard::map(lock.write(), |pair| &mut pair.1[1]);
+    *second += 40;
+    assert!(lock.try_read().is_none());
+    drop(second);
+
+    assert!(!lock.is_locked());
+    assert_eq!(lock.read().1, [1, 42, 3]);
+}
+
+#[test]
+fn into_inner() {
+    let mut lock = RwLock::new(vec![1]);
+    lock.get_mut().push(2);
+    lock.write().push(3);
+    assert_eq!(lock.into_inner(), [1, 2, 3]);
+}
+
+#[test]
+fn timed_read_write() {
+    use std::thread;
+
+    let lock = RwLock::new(0);
+
+    // A held write lock keeps both out until the timeout.
+    let guard = lock.write();
+    let start = Instant::now();
+    assert!(lock.try_read_for(Duration::from_millis(50)).is_none());
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert!(lock.try_write_for(Duration::from_millis(50)).is_none());
+    drop(guard);
+
+    // A writer that timed out behind a reader doesn't block new readers.
+    let reader = lock.read();
+    assert!(lock.try_write_for(Duration::from_millis(50)).is_none());
+    assert_eq!(lock.state.load(Relaxed) & 1, 0);
+    assert!(lock.try_read().is_some());
+    drop(reader);
+
+    // And both get in once the other side is gone.
+    thread::scope(|s| {
+        let guard = lock.write();
+        s.spawn(|| {
+            *lock.try_write_for(Duration::from_secs(10)).unwrap() += 1;
+        });
+        thread::sleep(Duration::from_millis(20));
+        drop(guard);
+        let value = *lock.try_read_for(Duration::from_secs(10)).unwrap();
+        assert!(value <= 1);
+    });
+    assert_eq!(*lock.read(), 1);
+}
+
+#[test]
+fn timed_writer_passes_on_wake() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let lock = RwLock::new(0);
+    let reader = lock.read();
+    thread::scope(|s| {
+        // The timed writer is first, alone in the line, and sleeps on the
+        // counter. Then a writer from the line gets in front of it.
+        s.spawn(|| {
+            *lock.try_write_for(Duration::from_secs(10)).unwrap() += 1;
+        });
+        thread::sleep(Duration::from_millis(50));
+        let queued = s.spawn(|| {
+            let start = Instant::now();
+            *lock.write() += 1;
+            start.elapsed()
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        // The reader wakes one writer. Whichever it is, the one from the
+        // line gets the lock right away, not after the other one times out.
+        drop(reader);
+        assert!(queued.join().unwrap() < Duration::from_secs(5));
+    });
+    assert_eq!(*lock.read(), 2);
+}
+
+#[test]
+fn timed_writer_gives_up_during_upgrade() {
+    use std::thread;
+    use std::time::Duration;
+
+    let lock = RwLock::new(0);
+    let mut guard = lock.read();
+    thread::scope(|s| {
+        // Marks the state as a writer waiting, then times out while the
+        // reader below is upgraded, when there is no odd bit to clear.
+        let writer = s.spawn(|| lock.try_write_for(Duration::from_millis(50)).is_none());
+        while lock.state.load(Relaxed) & 1 == 0 {
+            thread::yield_now();
+        }
+        guard.with_upgraded(|v| {
+            thread::sleep(Duration::from_millis(150));
+            *v += 1;
+        });
+        assert!(writer.join().unwrap());
+    });
+    drop(guard);
+
+    // The writer is long gone, nothing should be keeping the readers out.
+    assert_eq!(lock.state.load(Relaxed), 0);
+    assert_eq!(*lock.try_read_for(Duration::from_secs(1)).unwrap(), 1);
+}
@@ -0,0 +1,88 @@
+use crate::futex::{wait, wake_one};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+// A mutex lets one thread in at a time, a semaphore lets up to N in,
+// e.g. to limit how many threads use a pool of connections at once.
+pub struct Semaphore {
+    /// How many more threads can get in right now.
+    permits: AtomicU32,
+}
+
+pub struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub const fn new(permits: u32) -> Self {
+        Self {
+            permits: AtomicU32::new(permits),
+        }
+    }
+
+    pub fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut permits = self.permits.load(Relaxed);
+        loop {
+            if permits == 0 {
+                // The futex only sleeps if there are still no permits, so
+                // a release between the load and here isn't missed.
+                wait(&self.permits, 0);
+                permits = self.permits.load(Relaxed);
+                continue;
+            }
+            // Acquire pairs with the Release in release, same as a lock.
+            match self.permits.compare_exchange_weak(permits, permits - 1, Acquire, Relaxed) {
+                Ok(_) => return SemaphoreGuard { semaphore: self },
+                Err(e) => permits = e,
+            }
+        }
+    }
+
+    // Called by the guard's drop, so a permit is never released twice.
+    fn release(&self) {
+        self.permits.fetch_add(1, Release);
+        // One permit is enough for one waiter. If somebody else takes it
+        // first, the woken thread sees 0 and goes back to sleep, and the
+        // next release wakes the next one. Each release wakes someone, so
+        // as long as there are permits nobody is left sleeping.
+        wake_one(&self.permits);
+    }
+
+    // A snapshot, can be stale by the time the caller looks at it.
+    pub fn available_permits(&self) -> u32 {
+        self.permits.load(Relaxed)
+    }
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}
+
+#[test]
+fn limits_concurrency() {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    const PERMITS: usize = 3;
+    let semaphore = Semaphore::new(PERMITS as u32);
+    let running = AtomicUsize::new(0);
+    let peak = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..PERMITS + 2 {
+            s.spawn(|| {
+                for _ in 0..100 {
+                    let _permit = semaphore.acquire();
+                    let now = running.fetch_add(1, Relaxed) + 1;
+                    peak.fetch_max(now, Relaxed);
+                    thread::yield_now();
+                    running.fetch_sub(1, Relaxed);
+                }
+            });
+        }
+    });
+    assert!(peak.load(Relaxed) <= PERMITS);
+    assert!(peak.load(Relaxed) >= 1);
+    assert_eq!(semaphore.available_permits(), PERMITS as u32);
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use super::rwlock_3::RwLock;
+
+// Every shard sits on its own cache line. Otherwise the lock states of
+// neighbouring shards would share a cache line and threads working on
+// different shards would still be fighting over it (false sharing,
+// see the ch7 caching example).
+#[repr(align(64))]
+struct Shard<K, V> {
+    map: RwLock<HashMap<K, V>>,
+}
+
+// A single RwLock<HashMap> serializes all the writers even if they touch
+// completely different keys. Here the keys are split between several
+// independently locked maps, so operations on different shards can run
+// in parallel and only keys that land in the same shard contend.
+pub struct ShardedMap<K, V> {
+    shards: Box<[Shard<K, V>]>,
+    // One hasher for the whole map so that a key is always
+    // hashed the same way and lands in the same shard.
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_shards(16)
+    }
+
+    pub fn with_shards(shards: usize) -> Self {
+        assert!(shards > 0, "need at least one shard");
+        Self {
+            shards: (0..shards).map(|_| Shard { map: RwLock::new(HashMap::new()) }).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &RwLock<HashMap<K, V>> {
+        let hash = self.hasher.hash_one(key);
+        &self.shards[(hash % self.shards.len() as u64) as usize].map
+    }
+
+    // The value is cloned since we can't hand out a reference
+    // that would outlive the read lock of the shard.
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.shard(key).read().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).write().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).write().remove(key)
+    }
+}
+
+impl<K: Hash + Eq, V> Default for ShardedMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Every thread on its own key range, so most of the time they are in
+// different shards at once. How much faster that is than a single lock
+// depends on the number of cores, the test only checks that nothing is
+// lost on the way.
+#[test]
+fn concurrent_inserts() {
+    use std::thread;
+
+    const THREADS: u32 = 8;
+    const KEYS: u32 = 10_000;
+
+    let sharded = ShardedMap::new();
+    thread::scope(|s| {
+        for t in 0..THREADS {
+            let sharded = &sharded;
+            s.spawn(move || {
+                // Disjoint key ranges for every thread.
+                for k in t * KEYS..(t + 1) * KEYS {
+                    sharded.insert(k, k);
+                    assert_eq!(sharded.get(&k), Some(k));
+                }
+            });
+        }
+    });
+
+    for k in 0..THREADS * KEYS {
+        assert_eq!(sharded.get(&k), Some(k));
+    }
+    assert_eq!(sharded.remove(&7), Some(7));
+    assert_eq!(sharded.remove(&7), None);
+    assert_eq!(sharded.insert(8, 0), Some(8));
+    assert_eq!(sharded.get(&8), Some(0));
+}
@@ -0,0 +1,236 @@
+// Our own futex wrapper, so the locks don't depend on atomic_wait directly.
+//
+// The functions have the same shape as in atomic_wait (and in
+// ch8-01-futex.rs), plus a wait with a timeout that atomic_wait lacks.
+// As with any futex, waits can return spuriously, so the caller has
+// to check the value again in a loop.
+//
+// On Linux it's the futex syscall. Everywhere else it's a fallback
+// built on std's Mutex and Condvar, see the table module below.
+
+#[cfg(target_os = "linux")]
+pub use linux::{wait, wait_timeout, wake_all, wake_one};
+
+#[cfg(not(target_os = "linux"))]
+pub use table::{wait, wait_timeout, wake_all, wake_one};
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::sync::atomic::AtomicU32;
+    use std::time::Duration;
+
+    // Private futexes are faster since the kernel doesn't need to look
+    // for other processes that could have the same memory mapped. That's
+    // what atomic_wait does too.
+    const WAIT: i32 = libc::FUTEX_WAIT | libc::FUTEX_PRIVATE_FLAG;
+    const WAKE: i32 = libc::FUTEX_WAKE | libc::FUTEX_PRIVATE_FLAG;
+
+    pub fn wait(a: &AtomicU32, expected: u32) {
+        unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                a as *const AtomicU32,
+                WAIT,
+                expected,
+                std::ptr::null::<libc::timespec>(),
+            );
+        }
+    }
+
+    // Returns false only if the timeout has passed. A wake, a spurious
+    // wakeup or a value that was already different all return true.
+    // So does a signal interrupting the syscall (EINTR), possibly long
+    // before the timeout. The caller that needs the full time has to
+    // keep a deadline and wait again for what's left of it.
+    pub fn wait_timeout(a: &AtomicU32, expected: u32, timeout: Duration) -> bool {
+        // Note that the futex timeout is relative, not a deadline.
+        let timeout = libc::timespec {
+            tv_sec: timeout.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+            tv_nsec: timeout.subsec_nanos() as libc::c_long,
+        };
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_futex,
+                a as *const AtomicU32,
+                WAIT,
+                expected,
+                &timeout as *const libc::timespec,
+            )
+        };
+        !(result == -1
+            && std::io::Error::last_os_error().raw_os_error() == Some(libc::ETIMEDOUT))
+    }
+
+    pub fn wake_one(a: &AtomicU32) {
+        unsafe {
+            libc::syscall(libc::SYS_futex, a as *const AtomicU32, WAKE, 1i32);
+        }
+    }
+
+    pub fn wake_all(a: &AtomicU32) {
+        unsafe {
+            libc::syscall(libc::SYS_futex, a as *const AtomicU32, WAKE, i32::MAX);
+        }
+    }
+}
+
+// The fallback keeps a fixed table of Mutex+Condvar pairs. An address is
+// hashed into the table, so unrelated atomics may share a slot. That's fine
+// since a waiter only gets an extra spurious wakeup because of that.
+//
+// The value check in wait and the wake both happen under the slot's mutex.
+// So a waiter either sees the new value or is already waiting on the
+// condvar by the time the wake comes, and no wake is lost.
+//
+// It's compiled for tests on Linux too, so we can test it there.
+#[cfg(any(test, not(target_os = "linux")))]
+mod table {
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+    use std::time::Duration;
+
+    struct Slot {
+        mutex: Mutex<()>,
+        condvar: Condvar,
+    }
+
+    static SLOTS: [Slot; 64] = [const {
+        Slot {
+            mutex: Mutex::new(()),
+            condvar: Condvar::new(),
+        }
+    }; 64];
+
+    fn slot(a: &AtomicU32) -> &'static Slot {
+        // The lowest two bits are always 0 for an aligned AtomicU32.
+        &SLOTS[(a as *const AtomicU32 as usize >> 2) % SLOTS.len()]
+    }
+
+    // Nothing is run under these mutexes that could panic,
+    // so poisoning can be ignored.
+    fn lock(slot: &Slot) -> MutexGuard<'_, ()> {
+        slot.mutex.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn wait(a: &AtomicU32, expected: u32) {
+        let slot = slot(a);
+        let guard = lock(slot);
+        if a.load(Relaxed) == expected {
+            drop(slot.condvar.wait(guard));
+        }
+    }
+
+    pub fn wait_timeout(a: &AtomicU32, expected: u32, timeout: Duration) -> bool {
+        let slot = slot(a);
+        let guard = lock(slot);
+        if a.load(Relaxed) != expected {
+            return true;
+        }
+        let (guard, result) = slot
+            .condvar
+            .wait_timeout(guard, timeout)
+            .unwrap_or_else(PoisonError::into_inner);
+        drop(guard);
+        !result.timed_out()
+    }
+
+    // All the waiters of the slot are woken, since we can't
+    // tell which of them wait for this particular address.
+    pub fn wake_one(a: &AtomicU32) {
+        wake_all(a);
+    }
+
+    pub fn wake_all(a: &AtomicU32) {
+        let slot = slot(a);
+        drop(lock(slot));
+        slot.condvar.notify_all();
+    }
+}
+
+#[test]
+fn timeout_without_wake() {
+    use std::sync::atomic::AtomicU32;
+    use std::time::{Duration, Instant};
+
+    let a = AtomicU32::new(0);
+    for wait_timeout in [wait_timeout, table::wait_timeout] {
+        let start = Instant::now();
+        assert!(!wait_timeout(&a, 0, Duration::from_millis(50)));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // A different value returns right away.
+        assert!(wait_timeout(&a, 1, Duration::from_secs(10)));
+    }
+}
+
+// The syscall one on its own, with an upper bound too: FUTEX_WAIT takes
+// the timeout relative to now, so it must not be read as a deadline in
+// the past (returning at once) or far in the future.
+#[cfg(target_os = "linux")]
+#[test]
+fn linux_timeout_near_deadline() {
+    use std::sync::atomic::AtomicU32;
+    use std::time::{Duration, Instant};
+
+    let a = AtomicU32::new(7);
+    let start = Instant::now();
+    assert!(!linux::wait_timeout(&a, 7, Duration::from_millis(100)));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(100));
+    assert!(elapsed < Duration::from_secs(5));
+}
+
+#[test]
+fn wake_before_timeout() {
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+    use std::time::Duration;
+
+    let fns = [
+        (wait_timeout as fn(&AtomicU32, u32, Duration) -> bool, wake_one as fn(&AtomicU32)),
+        (table::wait_timeout, table::wake_one),
+    ];
+    for (wait_timeout, wake_one) in fns {
+        let a = AtomicU32::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                a.store(1, Relaxed);
+                wake_one(&a);
+            });
+            while a.load(Relaxed) == 0 {
+                assert!(wait_timeout(&a, 0, Duration::from_secs(10)));
+            }
+        });
+    }
+}
+
+#[test]
+fn wake_all_waiters() {
+    use std::sync::atomic::AtomicU32;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+    use std::time::Duration;
+
+    let fns = [
+        (wait as fn(&AtomicU32, u32), wake_all as fn(&AtomicU32)),
+        (table::wait, table::wake_all),
+    ];
+    for (wait, wake_all) in fns {
+        let a = AtomicU32::new(0);
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    while a.load(Relaxed) == 0 {
+                        wait(&a, 0);
+                    }
+                });
+            }
+            thread::sleep(Duration::from_millis(50));
+            a.store(1, Relaxed);
+            wake_all(&a);
+        });
+    }
+}
@@ -0,0 +1,77 @@
+use crate::futex::{wait, wake_all};
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Release};
+
+// The READY flag from ch3-06 as a type, with a futex instead of the sleep
+// loop. Whatever a thread did before open is visible to everyone that gets
+// through wait, same as the DATA there.
+//
+// A one way latch: once open it stays open. Unlike CountDownLatch it
+// doesn't count anything, any thread can open it, and opening it again
+// does nothing.
+pub struct Gate {
+    /// 0 while closed, 1 once open. Not an AtomicBool, a futex needs 32 bits.
+    open: AtomicU32,
+}
+
+impl Gate {
+    pub const fn new() -> Self {
+        Self {
+            open: AtomicU32::new(0),
+        }
+    }
+
+    pub fn open(&self) {
+        // Release, everything from before this store ..
+        self.open.store(1, Release);
+        wake_all(&self.open);
+    }
+
+    pub fn wait(&self) {
+        // .. is visible after this loads 1.
+        while self.open.load(Acquire) == 0 {
+            // Returns right away if the gate was opened since the load.
+            wait(&self.open, 0);
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Acquire) == 1
+    }
+}
+
+impl Default for Gate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn opens_for_everyone() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+
+    const N: usize = 8;
+    let gate = Gate::new();
+    let through = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..N {
+            s.spawn(|| {
+                gate.wait();
+                assert!(gate.is_open());
+                through.fetch_add(1, Relaxed);
+            });
+        }
+        thread::yield_now();
+        assert_eq!(through.load(Relaxed), 0);
+        assert!(!gate.is_open());
+        gate.open();
+    });
+    assert_eq!(through.load(Relaxed), N);
+
+    // Stays open, nobody waits anymore.
+    gate.wait();
+    gate.open();
+    assert!(gate.is_open());
+}
@@ -0,0 +1,78 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+
+// Where Stats gives min/max/avg, this shows how the values spread, e.g.
+// whether a slow average is all the calls being a bit slow or a few of
+// them being very slow.
+//
+// The buckets are powers of two: bucket 0 is the value 0, and bucket i
+// is 2^(i-1)..2^i. So 1 is in bucket 1, 2..4 in bucket 2, 4..8 in bucket 3
+// and so on. That's coarse, but the range grows quickly with few buckets,
+// and for timings the order of magnitude is what matters. The last bucket
+// also takes everything that is too big for the others.
+//
+// Each bucket is counted on its own, same as the fields of Stats. A
+// snapshot taken while others record can have some buckets before and
+// some after a record, but each record lands in exactly one bucket.
+pub struct Histogram<const BUCKETS: usize> {
+    counts: [AtomicU64; BUCKETS],
+}
+
+impl<const BUCKETS: usize> Histogram<BUCKETS> {
+    pub const fn new() -> Self {
+        assert!(BUCKETS > 0, "a histogram needs at least one bucket");
+        Self {
+            counts: [const { AtomicU64::new(0) }; BUCKETS],
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        self.counts[Self::bucket(value)].fetch_add(1, Relaxed);
+    }
+
+    pub fn snapshot(&self) -> [u64; BUCKETS] {
+        std::array::from_fn(|i| self.counts[i].load(Relaxed))
+    }
+
+    // The number of bits needed for the value, clamped to the last bucket.
+    pub const fn bucket(value: u64) -> usize {
+        let bits = (u64::BITS - value.leading_zeros()) as usize;
+        if bits < BUCKETS { bits } else { BUCKETS - 1 }
+    }
+}
+
+impl<const BUCKETS: usize> Default for Histogram<BUCKETS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn concurrent_record() {
+    use std::thread;
+
+    assert_eq!(Histogram::<8>::bucket(0), 0);
+    assert_eq!(Histogram::<8>::bucket(1), 1);
+    assert_eq!(Histogram::<8>::bucket(3), 2);
+    assert_eq!(Histogram::<8>::bucket(4), 3);
+    // 64 needs 7 bits, the last bucket takes it and anything bigger.
+    assert_eq!(Histogram::<8>::bucket(64), 7);
+    assert_eq!(Histogram::<8>::bucket(u64::MAX), 7);
+
+    let histogram = Histogram::<8>::new();
+    thread::scope(|s| {
+        for t in 0..4 {
+            let histogram = &histogram;
+            s.spawn(move || {
+                for i in 0..250 {
+                    histogram.record(t * 250 + i);
+                }
+            });
+        }
+    });
+
+    let snapshot = histogram.snapshot();
+    assert_eq!(snapshot.iter().sum::<u64>(), 1000);
+    // 0, 1, 2..4, 4..8, 8..16, 16..32, 32..64, and the rest.
+    assert_eq!(snapshot, [1, 1, 2, 4, 8, 16, 32, 936]);
+}
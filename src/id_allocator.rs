@@ -0,0 +1,69 @@
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::Relaxed;
+
+// The id allocation from ch2-10 and ch2-12 as a type, so the limit isn't
+// hard coded and each allocator has its own counter.
+//
+// ch2-10 does a fetch_add and then checks the limit. A thread that lost
+// the race at the boundary has already consumed an id by then, and it has
+// to subtract it back, while other threads could see the counter past
+// the limit. Here the counter is only ever incremented when the id is
+// actually handed out, so a failed allocation changes nothing.
+pub struct IdAllocator {
+    next: AtomicU32,
+    /// Ids are handed out from 0 up to, but not including, max.
+    max: u32,
+}
+
+impl IdAllocator {
+    pub const fn new(max: u32) -> Self {
+        Self {
+            next: AtomicU32::new(0),
+            max,
+        }
+    }
+
+    // Relaxed is enough, we only need every id to be unique
+    // and that's guaranteed by the total modification order.
+    pub fn allocate(&self) -> Option<u32> {
+        // fetch_update is the compare_exchange_weak loop from ch2-12.
+        // Returning None from the closure stops it without storing anything.
+        self.next
+            .fetch_update(Relaxed, Relaxed, |n| if n < self.max { Some(n + 1) } else { None })
+            .ok()
+    }
+
+    pub fn allocate_or_panic(&self) -> u32 {
+        self.allocate().expect("too many IDs!")
+    }
+}
+
+#[test]
+fn exactly_max_ids() {
+    use std::collections::HashSet;
+    use std::thread;
+
+    let allocator = IdAllocator::new(1000);
+    let ids: Vec<Option<u32>> = thread::scope(|s| {
+        let threads: Vec<_> = (0..4)
+            .map(|_| s.spawn(|| (0..300).map(|_| allocator.allocate()).collect::<Vec<_>>()))
+            .collect();
+        threads.into_iter().flat_map(|t| t.join().unwrap()).collect()
+    });
+
+    let given: HashSet<u32> = ids.iter().flatten().copied().collect();
+    assert_eq!(ids.iter().flatten().count(), 1000);
+    assert_eq!(given, (0..1000).collect());
+    assert_eq!(ids.iter().filter(|id| id.is_none()).count(), 200);
+
+    // Failed attempts didn't push the counter past the limit.
+    assert_eq!(allocator.next.load(Relaxed), 1000);
+}
+
+#[test]
+#[should_panic(expected = "too many IDs!")]
+fn allocate_or_panic() {
+    let allocator = IdAllocator::new(1);
+    assert_eq!(allocator.allocate_or_panic(), 0);
+    allocator.allocate_or_panic();
+}
@@ -2,3 +2,13 @@ pub mod ch4_spin_lock;
 pub mod ch5_channels;
 pub mod ch6_arc;
 pub mod ch9_locks;
+pub mod backoff;
+pub mod bench;
+pub mod futex;
+pub mod gate;
+pub mod histogram;
+pub mod id_allocator;
+pub mod once;
+pub mod stats;
+pub mod treiber_stack;
+pub mod weak_cache;
@@ -0,0 +1,136 @@
+use crate::futex::{wait, wake_all};
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering::{Acquire, Release};
+
+// A reusable version of the lazy initialization from ch2-13 and ch3-09.
+//
+// The examples let every thread that finds the value missing run the
+// initializer, and only one result wins the compare_exchange. The others
+// are dropped. That's fine for cheap initializers without side effects,
+// but here the closure runs at most once: the first thread to arrive
+// initializes and the rest wait on a futex until it's done.
+pub struct OnceCell<T> {
+    /// 0: empty
+    /// 1: being initialized, other threads are waiting
+    /// 2: initialized, the value is there
+    state: AtomicU32,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// The value is shared between threads (Sync) and is created on one thread
+// but can be dropped on another (Send).
+unsafe impl<T> Sync for OnceCell<T> where T: Send + Sync {}
+
+const EMPTY: u32 = 0;
+const RUNNING: u32 = 1;
+const READY: u32 = 2;
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU32::new(EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        // Acquire pairs with the Release store of READY,
+        // so the value written before it is visible.
+        if self.state.load(Acquire) == READY {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn get_or_init<F: FnOnce() -> T>(&self, f: F) -> &T {
+        let mut f = Some(f);
+        loop {
+            match self.state.compare_exchange(EMPTY, RUNNING, Acquire, Acquire) {
+                Ok(_) => {
+                    // If f panics the cell goes back to empty and the
+                    // next waiter gets to try its own initializer.
+                    struct Reset<'a>(&'a AtomicU32);
+
+                    impl Drop for Reset<'_> {
+                        fn drop(&mut self) {
+                            self.0.store(EMPTY, Release);
+                            wake_all(self.0);
+                        }
+                    }
+
+                    let reset = Reset(&self.state);
+                    let value = (f.take().unwrap())();
+                    std::mem::forget(reset);
+
+                    // Safety: We are the only thread in the RUNNING state.
+                    unsafe { (*self.value.get()).write(value) };
+                    self.state.store(READY, Release);
+                    wake_all(&self.state);
+                }
+                Err(RUNNING) => wait(&self.state, RUNNING),
+                Err(_) => {}
+            }
+            if let Some(value) = self.get() {
+                return value;
+            }
+        }
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            unsafe { self.value.get_mut().assume_init_drop() }
+        }
+    }
+}
+
+#[test]
+fn initialized_once() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+    use std::thread;
+
+    let cell = OnceCell::new();
+    let runs = AtomicUsize::new(0);
+    assert!(cell.get().is_none());
+
+    thread::scope(|s| {
+        for i in 0..8 {
+            let cell = &cell;
+            let runs = &runs;
+            s.spawn(move || {
+                let value = cell.get_or_init(|| {
+                    runs.fetch_add(1, Relaxed);
+                    thread::sleep(std::time::Duration::from_millis(50));
+                    vec![i; 100]
+                });
+                assert_eq!(value.len(), 100);
+            });
+        }
+    });
+
+    assert_eq!(runs.load(Relaxed), 1);
+    let value = cell.get().unwrap();
+    assert!(value.iter().all(|&x| x == value[0]));
+}
+
+#[test]
+fn panicking_initializer() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let cell = OnceCell::new();
+    let result = catch_unwind(AssertUnwindSafe(|| cell.get_or_init(|| panic!("oops"))));
+    assert!(result.is_err());
+    assert!(cell.get().is_none());
+    assert_eq!(*cell.get_or_init(|| String::from("second")), "second");
+}
@@ -0,0 +1,109 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+
+// The progress statistics from ch2-07 as a type that can be shared
+// between the worker threads and the one that reports.
+//
+// Each field is its own atomic, so recording a value is four separate
+// operations and a snapshot is four separate loads. A snapshot taken
+// while others record can see a value counted in total but not yet in
+// count, or a new max with the old total, so the avg can be a bit off and
+// min/max can be ahead of count. Nothing gets lost though, once everybody
+// is done recording the snapshot is exact. A Mutex would make it
+// consistent, but then the recording threads would start blocking each other.
+pub struct Stats {
+    count: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+    /// u64::MAX while nothing was recorded.
+    min: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub count: u64,
+    pub total: u64,
+    /// 0 when nothing was recorded yet, same as max and avg.
+    pub min: u64,
+    pub max: u64,
+    pub avg: u64,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    // Relaxed everywhere, the values don't guard any other data.
+    pub fn record(&self, micros: u64) {
+        self.count.fetch_add(1, Relaxed);
+        self.total.fetch_add(micros, Relaxed);
+        self.max.fetch_max(micros, Relaxed);
+        self.min.fetch_min(micros, Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        // With Relaxed there's no order between the four loads and the
+        // four updates in record, so any mix of old and new values is
+        // possible. The only thing to be careful about is the division.
+        let count = self.count.load(Relaxed);
+        let total = self.total.load(Relaxed);
+        let max = self.max.load(Relaxed);
+        let min = self.min.load(Relaxed);
+        if count == 0 {
+            return StatsSnapshot { count: 0, total: 0, min: 0, max: 0, avg: 0 };
+        }
+        StatsSnapshot {
+            count,
+            total,
+            // A racing record could have bumped count but not min yet.
+            min: if min == u64::MAX { max } else { min },
+            max,
+            avg: total / count,
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn concurrent_record() {
+    use std::thread;
+
+    let stats = Stats::new();
+    assert_eq!(stats.snapshot().count, 0);
+
+    thread::scope(|s| {
+        for t in 0..4 {
+            let stats = &stats;
+            s.spawn(move || {
+                for i in 1..=25 {
+                    stats.record(t * 100 + i);
+                }
+            });
+        }
+        // Mid flight snapshots are only roughly consistent,
+        // but min and max still bound the recorded set.
+        let snapshot = stats.snapshot();
+        if snapshot.count > 0 {
+            assert!(snapshot.min >= 1 && snapshot.max <= 325);
+        }
+    });
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.count, 100);
+    assert_eq!(snapshot.min, 1);
+    assert_eq!(snapshot.max, 325);
+    let expected_total: u64 = (0..4).map(|t| (1..=25).map(|i| t * 100 + i).sum::<u64>()).sum();
+    assert_eq!(snapshot.total, expected_total);
+    assert_eq!(snapshot.avg, expected_total / 100);
+}
@@ -0,0 +1,253 @@
+use std::mem::ManuallyDrop;
+use std::ptr;
+use std::sync::atomic::Ordering::{Relaxed, Release, SeqCst};
+use std::sync::atomic::{AtomicPtr, AtomicUsize};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+// A lock-free stack, the classic one by Treiber. The head is a single
+// pointer and both push and pop are a compare_exchange loop on it, nobody
+// ever blocks. If a thread gets preempted in the middle, the others
+// just win their exchanges and go on.
+//
+// The hard part is not the exchange but the memory. pop reads head.next
+// before its exchange, and by that time another pop could have taken the
+// same node and freed it. That's a read of freed memory. Worse, the
+// allocator could give that memory to a new push, so the head is again
+// the same pointer, but with another next (the ABA problem). Our
+// exchange compares pointers only, it succeeds, and the head becomes
+// the stale next, which can be a node that is already gone.
+//
+// The first version leaked every popped node to avoid that. Now a popped
+// node is retired instead: it goes to a list and is freed later, once no
+// pop is running. Only a pop can hold a pointer to a node that is not in
+// the stack anymore, and it got that pointer while it was running. So
+// when nobody pops, nobody can look at the retired nodes. And as long as
+// a node isn't freed its address can't come back, so no ABA either.
+//
+// It's the simplest kind of safe memory reclamation there is. The catch:
+// with pops going on all the time the count may never get to zero, and
+// the retired list just grows. Hazard pointers or epochs (as in
+// crossbeam) don't have that problem, but they are a lot more code.
+pub struct Stack<T> {
+    head: AtomicPtr<Node<T>>,
+    /// Unlinked nodes, their values already taken. Freed when it's safe.
+    retired: Mutex<Vec<*mut Node<T>>>,
+    /// How many pops are running right now.
+    active_pops: AtomicUsize,
+}
+
+struct Node<T> {
+    /// Taken out by the pop that unlinks the node. The node itself stays.
+    value: ManuallyDrop<T>,
+    /// Never changed once the node is in the stack.
+    next: *mut Node<T>,
+}
+
+// The values move between threads through the stack, same as with a
+// channel. The raw pointers in the retired list are only ours.
+unsafe impl<T: Send> Send for Stack<T> {}
+unsafe impl<T: Send> Sync for Stack<T> {}
+
+impl<T> Stack<T> {
+    pub const fn new() -> Self {
+        Self {
+            head: AtomicPtr::new(ptr::null_mut()),
+            retired: Mutex::new(Vec::new()),
+            active_pops: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let node = Box::into_raw(Box::new(Node {
+            value: ManuallyDrop::new(value),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.head.load(Relaxed);
+        loop {
+            // Nobody sees the node before the exchange, we can still write it.
+            unsafe { (*node).next = head };
+            // Release so that a pop that gets this node also sees its fields.
+            match self.head.compare_exchange_weak(head, node, Release, Relaxed) {
+                Ok(_) => return,
+                Err(new_head) => head = new_head,
+            }
+        }
+    }
+
+    // Everything about the head and the count here is SeqCst. The argument
+    // for freeing is "nobody that started after the node was unlinked can
+    // see it", and that needs our increment to come before our load of the
+    // head, and the unlink before the check of the count. A store and a
+    // later load to another atomic are ordered only with SeqCst.
+    pub fn pop(&self) -> Option<T> {
+        self.active_pops.fetch_add(1, SeqCst);
+
+        let mut head = self.head.load(SeqCst);
+        let value = loop {
+            if head.is_null() {
+                break None;
+            }
+            // The node could already be popped by someone else, but it
+            // can't be freed while we are counted, so reading it is fine.
+            // If it was popped, the exchange below fails.
+            let next = unsafe { (*head).next };
+            match self.head.compare_exchange_weak(head, next, SeqCst, SeqCst) {
+                Ok(_) => {
+                    // Only the winner of the exchange gets here, once per node.
+                    let value = unsafe { ManuallyDrop::take(&mut (*head).value) };
+                    self.retired().push(head);
+                    break Some(value);
+                }
+                Err(new_head) => head = new_head,
+            }
+        };
+
+        // Take the list before we stop counting, not after. Another pop
+        // can start right after our decrement, read a head that is being
+        // unlinked and retired, and that node must not be in our list.
+        // Everything in it now was unlinked before our check, so a pop that
+        // could still see it had to start before and would be counted.
+        let mut retired = if self.active_pops.load(SeqCst) == 1 {
+            std::mem::take(&mut *self.retired())
+        } else {
+            Vec::new()
+        };
+        if self.active_pops.fetch_sub(1, SeqCst) == 1 {
+            for node in retired.drain(..) {
+                // The value is gone already, only the node is freed.
+                drop(unsafe { Box::from_raw(node) });
+            }
+        } else if !retired.is_empty() {
+            // Somebody came in between, they will free it later.
+            self.retired().append(&mut retired);
+        }
+        value
+    }
+
+    // Nothing panics while the list is locked,
+    // so it can't be poisoned.
+    fn retired(&self) -> MutexGuard<'_, Vec<*mut Node<T>>> {
+        self.retired.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // A snapshot, can be stale by the time the caller looks at it.
+    pub fn is_empty(&self) -> bool {
+        self.head.load(Relaxed).is_null()
+    }
+}
+
+impl<T> Default for Stack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Stack<T> {
+    fn drop(&mut self) {
+        // Only the nodes that are still linked have their value.
+        let mut node = *self.head.get_mut();
+        while !node.is_null() {
+            let mut boxed = unsafe { Box::from_raw(node) };
+            node = boxed.next;
+            unsafe { ManuallyDrop::drop(&mut boxed.value) };
+        }
+        // The rest have no values anymore, just the nodes.
+        let retired = self.retired.get_mut().unwrap_or_else(PoisonError::into_inner);
+        for node in retired.drain(..) {
+            drop(unsafe { Box::from_raw(node) });
+        }
+    }
+}
+
+#[test]
+fn count_is_conserved() {
+    use std::thread;
+
+    const THREADS: usize = 4;
+    const PER_THREAD: usize = 1000;
+
+    let stack = Stack::new();
+    let popped = AtomicUsize::new(0);
+    let sum = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for t in 0..THREADS {
+            let (stack, popped, sum) = (&stack, &popped, &sum);
+            s.spawn(move || {
+                for i in 0..PER_THREAD {
+                    stack.push(t * PER_THREAD + i);
+                    // Pop about half as we go, so pushes and pops race.
+                    if i & 1 == 1 {
+                        if let Some(value) = stack.pop() {
+                            popped.fetch_add(1, Relaxed);
+                            sum.fetch_add(value, Relaxed);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let mut rest = 0;
+    while let Some(value) = stack.pop() {
+        rest += 1;
+        sum.fetch_add(value, Relaxed);
+    }
+    assert!(stack.is_empty());
+
+    // Every value came out exactly once.
+    let n = THREADS * PER_THREAD;
+    assert_eq!(popped.load(Relaxed) + rest, n);
+    assert_eq!(sum.load(Relaxed), n * (n - 1) / 2);
+}
+
+#[test]
+fn retired_nodes_are_freed() {
+    use std::thread;
+
+    // Drop counts every value dropped. A value dropped twice (or a node
+    // freed with its value still in it) shows up as too many drops.
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+    struct Counted;
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Relaxed);
+        }
+    }
+
+    // Small enough for a run under a sanitizer.
+    const THREADS: usize = 4;
+    const PER_THREAD: usize = 500;
+
+    let stack = Stack::new();
+    thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for _ in 0..PER_THREAD {
+                    stack.push(Counted);
+                }
+            });
+            s.spawn(|| {
+                let mut popped = 0;
+                while popped < PER_THREAD {
+                    match stack.pop() {
+                        Some(_) => popped += 1,
+                        None => thread::yield_now(),
+                    }
+                }
+            });
+        }
+    });
+    assert!(stack.is_empty());
+    assert_eq!(DROPS.load(Relaxed), THREADS * PER_THREAD);
+
+    // Nobody pops anymore, so the next pop frees everything retired.
+    stack.push(Counted);
+    assert!(stack.pop().is_some());
+    assert!(stack.retired().is_empty());
+    assert_eq!(DROPS.load(Relaxed), THREADS * PER_THREAD + 1);
+
+    // A value still in the stack is dropped with it, and only once.
+    stack.push(Counted);
+    drop(stack);
+    assert_eq!(DROPS.load(Relaxed), THREADS * PER_THREAD + 2);
+}
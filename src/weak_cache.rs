@@ -0,0 +1,100 @@
+use crate::ch6_arc::s2_weak::{Arc, Weak};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+// Hands out shared objects by key, the same Arc to everyone who asks for
+// the same key while it's in use. The cache itself keeps only a Weak, so
+// it doesn't keep anything alive: once the last user drops its Arc the
+// object is gone, and the next lookup builds a new one.
+//
+// Entries whose object is gone stay in the map until the next insert
+// cleans them up. That's one pass over the map per insert, so it's meant
+// for a cache of moderate size, not for millions of keys.
+pub struct WeakCache<K, T> {
+    map: Mutex<HashMap<K, Weak<T>>>,
+}
+
+impl<K: Eq + Hash, T> WeakCache<K, T> {
+    pub fn new() -> Self {
+        Self {
+            map: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // f runs with the map locked. That's what makes sure two threads that
+    // miss at the same time don't both build an object for the same key.
+    // But it also means f must not use the cache itself, that would
+    // deadlock.
+    pub fn get_or_insert_with<F: FnOnce() -> T>(&self, key: K, f: F) -> Arc<T> {
+        let mut map = self.map();
+        if let Some(arc) = map.get(&key).and_then(Weak::upgrade) {
+            return arc;
+        }
+        // Could be checked with upgrade too, but then the dropped Arc can
+        // be the last one, and the object would be dropped with the map
+        // locked. strong_count doesn't touch the object at all.
+        map.retain(|_, weak| weak.strong_count() > 0);
+        let arc = Arc::new(f());
+        map.insert(key, Arc::downgrade(&arc));
+        arc
+    }
+
+    // Counts the entries that are dead but not pruned yet too.
+    pub fn len(&self) -> usize {
+        self.map().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map().is_empty()
+    }
+
+    // A panic in f leaves the map as it was, the insert comes after f.
+    // So a poisoned map is still a good one.
+    fn map(&self) -> MutexGuard<'_, HashMap<K, Weak<T>>> {
+        self.map.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<K: Eq + Hash, T> Default for WeakCache<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn rebuilds_after_last_drop() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::Relaxed;
+
+    let cache = WeakCache::new();
+    let builds = AtomicUsize::new(0);
+    let build = |name: &str| {
+        builds.fetch_add(1, Relaxed);
+        name.to_uppercase()
+    };
+
+    let a = cache.get_or_insert_with("a", || build("a"));
+    let a2 = cache.get_or_insert_with("a", || build("a"));
+    assert!(Arc::ptr_eq(&a, &a2));
+    assert_eq!(*a, "A");
+    assert_eq!(builds.load(Relaxed), 1);
+
+    // Still alive through a2.
+    drop(a);
+    let a3 = cache.get_or_insert_with("a", || build("a"));
+    assert!(Arc::ptr_eq(&a2, &a3));
+    assert_eq!(builds.load(Relaxed), 1);
+
+    // All gone, so the next lookup builds it again.
+    drop((a2, a3));
+    let a4 = cache.get_or_insert_with("a", || build("a"));
+    assert_eq!(*a4, "A");
+    assert_eq!(builds.load(Relaxed), 2);
+
+    // The dead entry of "b" is pruned by the insert of "c".
+    drop(cache.get_or_insert_with("b", || build("b")));
+    assert_eq!(cache.len(), 2);
+    let _c = cache.get_or_insert_with("c", || build("c"));
+    assert_eq!(cache.len(), 2);
+}